@@ -0,0 +1,6 @@
+# self-signed certificate for trying --tls-port locally:
+#   voicemail --tls-port 5061 --tls-cert Docker/test_cert.pem --tls-key Docker/test_key.pem
+cd "$(dirname "$0")"
+openssl req -x509 -newkey rsa:2048 -nodes -days 365 \
+  -subj "/CN=${1:-localhost}" -addext "subjectAltName=DNS:${1:-localhost},IP:127.0.0.1" \
+  -keyout test_key.pem -out test_cert.pem
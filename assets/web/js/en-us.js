@@ -8,4 +8,30 @@ export const READ_ERROR = "Read error: "
 export const LOAD_ERROR = "Failed to load."
 export const DEL_REC = "Do you want to delete this recording?"
 export const DEL_FAILED = "Deletion failed."
-export const DEL_ERROR = "Deletion error:"
\ No newline at end of file
+export const DEL_ERROR = "Deletion error:"
+export const QUALITY = "Call quality"
+export const LOSS = "loss"
+export const JITTER = "jitter"
+export const GREETINGS = "Greetings"
+export const GREETING_NAME = "name"
+export const UPLOAD = "Upload"
+export const BUILT_IN = "built-in"
+export const CALL_BACK = "call back"
+export const CALL_CONFIRM = "Ring your phone and call this caller back?"
+export const CALL_FAILED = "Call failed: "
+export const DIAL_STATE = {
+    calling_you: "calling your phone...",
+    calling_back: "calling the caller...",
+    ringing: "ringing...",
+    connected: "connected",
+    ended: "ended",
+    failed: "failed",
+}
+export const NO_ACCOUNT = "No SIP account"
+export const ALL_MAILBOXES = "all mailboxes"
+export const DEFAULT_GREETING = "The greeting chosen here plays in every mailbox without one of its own."
+export const REG_STATE = {
+    registering: "registering...",
+    registered: "registered",
+    retrying: "registration failed, retrying",
+}
\ No newline at end of file
@@ -97,6 +97,40 @@ function ulawToPCM(data, bit = 16) {
     return dest
 }
 
+function alaw2linear(a_val) {
+    let t
+    let seg
+    a_val ^= 0x55
+    t = (a_val & QUANT_MASK) << 4
+    seg = (a_val & SEG_MASK) >>> SEG_SHIFT
+    switch (seg) {
+        case 0:
+            t += 8
+            break
+        case 1:
+            t += 0x108
+            break
+        default:
+            t += 0x108
+            t <<= seg - 1
+    }
+    return (a_val & SIGN_BIT) ? t : -t
+}
+
+/**
+ *
+ * @param {Int8Array} data
+ * @returns
+ */
+function alawToPCM(data, bit = 16) {
+    let typedArray = bit === 16 ? Int16Array : Int8Array
+    let dest = new typedArray(data.length)
+    for (let i = 0, k = 0, len = data.length; i < len; i++) {
+        dest[k++] = alaw2linear(data[i] & 0xff)
+    }
+    return dest
+}
+
 /**
  *
  * @param { Int16Array or Int8Array } data
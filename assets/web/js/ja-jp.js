@@ -8,4 +8,30 @@ export const READ_ERROR = "読み込みエラー:"
 export const LOAD_ERROR = "読み込みに失敗しました。"
 export const DEL_REC = "この録音を削除しますか？"
 export const DEL_FAILED = "削除に失敗しました"
-export const DEL_ERROR = "削除エラー:"
\ No newline at end of file
+export const DEL_ERROR = "削除エラー:"
+export const QUALITY = "通話品質"
+export const LOSS = "損失"
+export const JITTER = "ジッター"
+export const GREETINGS = "応答メッセージ"
+export const GREETING_NAME = "名前"
+export const UPLOAD = "アップロード"
+export const BUILT_IN = "標準"
+export const CALL_BACK = "折り返し"
+export const CALL_CONFIRM = "あなたの電話を呼び出して折り返し発信しますか？"
+export const CALL_FAILED = "発信失敗: "
+export const DIAL_STATE = {
+    calling_you: "あなたの電話を呼び出し中...",
+    calling_back: "相手に発信中...",
+    ringing: "相手を呼び出し中...",
+    connected: "通話中",
+    ended: "終了",
+    failed: "失敗",
+}
+export const NO_ACCOUNT = "SIPアカウント未設定"
+export const ALL_MAILBOXES = "すべてのメールボックス"
+export const DEFAULT_GREETING = "ここで選んだ応答メッセージは、個別の設定がないすべてのメールボックスで流れます。"
+export const REG_STATE = {
+    registering: "登録中...",
+    registered: "登録済み",
+    retrying: "登録失敗、再試行中",
+}
\ No newline at end of file
@@ -14,7 +14,10 @@ async function togglePlay(id, btn) {
     const audioUrl = `/api/voice/${id}`;
     const res = await fetch(audioUrl);
     const arrayBuffer = await res.arrayBuffer();
-    const i16A = ulawToPCM(new Uint8Array(arrayBuffer), 16);
+    const alaw = res.headers.get('Content-Type') === 'audio/x-alaw-basic';
+    const i16A = alaw
+        ? alawToPCM(new Uint8Array(arrayBuffer), 16)
+        : ulawToPCM(new Uint8Array(arrayBuffer), 16);
     const wavBuf = encodeWAV(new DataView(i16A.buffer), 8000, 1, 16);
     const ctx = new (window.AudioContext || window.webkitAudioContext)();
 
@@ -1,5 +1,8 @@
+use crate::sip::call::Calls;
+use crate::sip::dial::Dialer;
+use crate::sip::register::RegStatus;
 use crate::sip::voice_mail;
-use crate::web::db::Pool;
+use crate::web::db::{Pool, add_columns};
 use actix_web::rt;
 use anyhow::Result;
 use r2d2_sqlite::SqliteConnectionManager;
@@ -10,6 +13,7 @@ mod utils;
 mod web;
 mod sms;
 mod speech_to_text;
+mod text_to_speech;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -23,20 +27,84 @@ async fn main() -> Result<()> {
                     event_time TEXT NOT NULL DEFAULT current_timestamp,
                     caller TEXT,
                     time INTEGER,
+                    codec TEXT NOT NULL DEFAULT 'PCMU',
+                    packets INTEGER,
+                    lost INTEGER,
+                    jitter REAL,
+                    rtt REAL,
+                    mos REAL,
+                    heard INTEGER NOT NULL DEFAULT 0,
+                    mailbox TEXT NOT NULL DEFAULT '',
                     data BLOB
                 );
                 create table if not exists contacts (
                     caller TEXT PRIMARY KEY,
                     name TEXT
                 );
+                create table if not exists mailbox (
+                    name TEXT PRIMARY KEY,
+                    max_length INTEGER,
+                    min_length INTEGER,
+                    warning INTEGER,
+                    greeting TEXT,
+                    play_greeting INTEGER,
+                    pin TEXT,
+                    owner TEXT,
+                    notify INTEGER,
+                    sns_topic TEXT,
+                    ai_models TEXT
+                );
+                create table if not exists greeting_rule (
+                    id INTEGER PRIMARY KEY,
+                    greeting TEXT NOT NULL,
+                    mailbox TEXT,
+                    caller TEXT,
+                    known INTEGER,
+                    days TEXT,
+                    start_time TEXT,
+                    end_time TEXT,
+                    start_date TEXT,
+                    end_date TEXT,
+                    priority INTEGER
+                );
+                create table if not exists tts_cache (
+                    text TEXT NOT NULL,
+                    voice TEXT NOT NULL,
+                    data BLOB,
+                    PRIMARY KEY (text, voice)
+                );
+                create table if not exists settings (
+                    name TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                );
             COMMIT;",
-        )
+        )?;
+        add_columns(c, "voicemail", &[
+            ("codec", "TEXT NOT NULL DEFAULT 'PCMU'"),
+            ("packets", "INTEGER"),
+            ("lost", "INTEGER"),
+            ("jitter", "REAL"),
+            ("rtt", "REAL"),
+            ("mos", "REAL"),
+            ("heard", "INTEGER NOT NULL DEFAULT 0"),
+            ("mailbox", "TEXT NOT NULL DEFAULT ''"),
+        ])?;
+        add_columns(c, "mailbox", &[
+            ("pin", "TEXT"),
+            ("owner", "TEXT"),
+            ("notify", "INTEGER"),
+            ("sns_topic", "TEXT"),
+            ("ai_models", "TEXT"),
+        ])
     });
     let pool = Pool::new(manager)?;
+    let calls = Calls::default();
+    let (dialer, dial_requests) = Dialer::new();
+    let registration = RegStatus::default();
 
-    let srv = web::server(pool.clone());
+    let srv = web::server(pool.clone(), calls.clone(), dialer.clone(), registration.clone());
     rt::spawn(srv);
-    voice_mail(pool).await?;
+    voice_mail(pool, calls, dialer, dial_requests, registration).await?;
     Ok(())
 }
 
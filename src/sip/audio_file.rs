@@ -0,0 +1,410 @@
+use crate::sip::codec::Codec;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, RwLock},
+};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as DecodeError,
+    formats::FormatOptions, io::{MediaSource, MediaSourceStream}, meta::MetadataOptions,
+    probe::Hint,
+};
+
+const ASSETS: &str = "./assets";
+/// uploaded and recorded greetings, searched before the assets
+const GREETINGS: &str = "./greetings";
+/// formats tried for a greeting name without extension, after the raw
+/// file of the negotiated codec
+const EXTENSIONS: [&str; 6] = ["pcmu", "pcma", "wav", "au", "flac", "mp3"];
+const RATE: u32 = 8000;
+
+#[derive(Debug)]
+pub enum AudioError {
+    NotFound(String),
+    InvalidName(String),
+    Io(PathBuf, std::io::Error),
+    Format(PathBuf, String),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioError::NotFound(name) => write!(
+                f,
+                "greeting '{name}' not found, expected {ASSETS}/{name}.{{{}}}",
+                EXTENSIONS.join(",")
+            ),
+            AudioError::InvalidName(name) => {
+                write!(f, "invalid greeting name '{name}', use letters, digits, '-' and '_'")
+            }
+            AudioError::Io(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            AudioError::Format(path, e) => write!(f, "cannot decode {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+/// Decoded mono audio.
+#[derive(Debug)]
+pub struct Pcm {
+    pub rate: u32,
+    pub samples: Vec<i16>,
+}
+
+fn downmix(interleaved: &[i16], channels: usize) -> Vec<i16> {
+    interleaved
+        .chunks_exact(channels.max(1))
+        .map(|c| (c.iter().map(|s| *s as i32).sum::<i32>() / c.len() as i32) as i16)
+        .collect()
+}
+
+/// Sun/NeXT audio, which symphonia does not read.
+fn decode_au(data: &[u8]) -> Result<Pcm, String> {
+    let word = |i: usize| {
+        data.get(i..i + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or("truncated header")
+    };
+    if data.get(..4) != Some(b".snd") {
+        return Err("not an AU file".into());
+    }
+    let offset = word(4)? as usize;
+    let size = word(8)? as usize;
+    let encoding = word(12)?;
+    let rate = word(16)?;
+    let channels = word(20)? as usize;
+    let body = data.get(offset..).ok_or("data offset past the end")?;
+    // 0xffffffff means the size is unknown
+    let body = &body[..size.min(body.len())];
+    let interleaved = match encoding {
+        1 => Codec::Pcmu.decode_all(body),
+        27 => Codec::Pcma.decode_all(body),
+        2 => body.iter().map(|b| ((*b as i8) as i16) << 8).collect(),
+        3 => body
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .collect(),
+        e => return Err(format!("unsupported AU encoding {e}")),
+    };
+    if rate == 0 || channels == 0 {
+        return Err("invalid sample rate or channel count".into());
+    }
+    Ok(Pcm { rate, samples: downmix(&interleaved, channels) })
+}
+
+/// WAV, FLAC and MP3.
+fn decode_media(source: Box<dyn MediaSource>, ext: &str) -> Result<Pcm, String> {
+    let mss = MediaSourceStream::new(source, Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(ext);
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|e| e.to_string())?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or("no audio track")?;
+    let track_id = track.id;
+    let rate = track.codec_params.sample_rate.ok_or("unknown sample rate")?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+
+    let mut samples = vec![];
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // skip a corrupt frame, as players do
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        let channels = decoded.spec().channels.count();
+        let mut buf = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+        buf.copy_interleaved_ref(decoded);
+        samples.extend(downmix(buf.samples(), channels));
+    }
+    Ok(Pcm { rate, samples })
+}
+
+/// Convert to 8 kHz, averaging over each output period when downsampling
+/// so higher frequencies do not alias.
+pub fn resample(samples: &[i16], from: u32) -> Vec<i16> {
+    if from == RATE || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let ratio = from as f64 / RATE as f64;
+    let len = (samples.len() as f64 / ratio) as usize;
+    let at = |i: usize| samples[i.min(samples.len() - 1)] as f64;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * ratio;
+            let v = if ratio > 1.0 {
+                let start = pos as usize;
+                let end = ((pos + ratio) as usize).clamp(start + 1, samples.len());
+                (start..end).map(at).sum::<f64>() / (end - start) as f64
+            } else {
+                let j = pos as usize;
+                let frac = pos - j as f64;
+                at(j) * (1.0 - frac) + at(j + 1) * frac
+            };
+            v.round() as i16
+        })
+        .collect()
+}
+
+fn decode(path: &Path) -> Result<Pcm, AudioError> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let format = |e: String| AudioError::Format(path.to_owned(), e);
+    match ext.as_str() {
+        "pcmu" | "pcma" | "ul" | "al" | "au" | "snd" => {
+            let data = std::fs::read(path).map_err(|e| AudioError::Io(path.to_owned(), e))?;
+            match ext.as_str() {
+                "pcmu" | "ul" => Ok(Pcm { rate: RATE, samples: Codec::Pcmu.decode_all(&data) }),
+                "pcma" | "al" => Ok(Pcm { rate: RATE, samples: Codec::Pcma.decode_all(&data) }),
+                _ => decode_au(&data).map_err(format),
+            }
+        }
+        _ => {
+            let file = File::open(path).map_err(|e| AudioError::Io(path.to_owned(), e))?;
+            decode_media(Box::new(file), &ext).map_err(format)
+        }
+    }
+}
+
+/// A WAV written to a pipe cannot seek back to fill in its sizes, set
+/// them from the data actually received.
+fn fix_wav_sizes(data: &mut [u8]) {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return;
+    }
+    let len = data.len();
+    data[4..8].copy_from_slice(&((len - 8) as u32).to_le_bytes());
+    let mut pos = 12;
+    while pos + 8 <= len {
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        if &data[pos..pos + 4] == b"data" {
+            let actual = (len - pos - 8).min(size);
+            data[pos + 4..pos + 8].copy_from_slice(&(actual as u32).to_le_bytes());
+            return;
+        }
+        pos += 8 + size + size % 2;
+    }
+}
+
+/// Decode audio held in memory, such as the output of a synthesizer.
+pub fn decode_bytes(mut data: Vec<u8>, ext: &str) -> Result<Pcm, String> {
+    match ext {
+        "au" | "snd" => decode_au(&data),
+        _ => {
+            fix_wav_sizes(&mut data);
+            decode_media(Box::new(Cursor::new(data)), ext)
+        }
+    }
+}
+
+/// A path with an extension, or a name looked up in the greetings and
+/// then the assets directory.
+fn find(name: &str, codec: Codec) -> Result<PathBuf, AudioError> {
+    let path = Path::new(name);
+    if path.extension().is_some() {
+        return [path.to_owned(), Path::new(GREETINGS).join(path), Path::new(ASSETS).join(path)]
+            .into_iter()
+            .find(|p| p.is_file())
+            .ok_or_else(|| AudioError::NotFound(name.to_owned()));
+    }
+    [GREETINGS, ASSETS]
+        .into_iter()
+        .flat_map(|dir| {
+            std::iter::once(codec.ext())
+                .chain(EXTENSIONS)
+                .map(move |ext| Path::new(dir).join(format!("{name}.{ext}")))
+        })
+        .find(|p| p.is_file())
+        .ok_or_else(|| AudioError::NotFound(name.to_owned()))
+}
+
+type Cache = RwLock<HashMap<(String, Codec), Arc<Vec<u8>>>>;
+static CACHE: LazyLock<Cache> = LazyLock::new(Default::default);
+
+/// Greeting `name` encoded for `codec`, transcoded once and cached.
+pub fn load(name: &str, codec: Codec) -> Result<Arc<Vec<u8>>, AudioError> {
+    let key = (name.to_owned(), codec);
+    if let Some(data) = CACHE.read().ok().and_then(|c| c.get(&key).cloned()) {
+        return Ok(data);
+    }
+    let path = find(name, codec)?;
+    let data = if path.extension().and_then(|e| e.to_str()) == Some(codec.ext()) {
+        std::fs::read(&path).map_err(|e| AudioError::Io(path.clone(), e))?
+    } else {
+        let pcm = decode(&path)?;
+        codec.encode_all(&resample(&pcm.samples, pcm.rate))
+    };
+    if data.is_empty() {
+        return Err(AudioError::Format(path, "no audio".into()));
+    }
+    let data = Arc::new(data);
+    if let Ok(mut c) = CACHE.write() {
+        c.insert(key, data.clone());
+    }
+    Ok(data)
+}
+
+/// A greeting name usable as a file name.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Store 8 kHz audio as greeting `name`, replacing an earlier upload
+/// and shadowing an asset of the same name.
+pub fn save(name: &str, samples: &[i16]) -> Result<(), AudioError> {
+    if !valid_name(name) {
+        return Err(AudioError::InvalidName(name.to_owned()));
+    }
+    let dir = Path::new(GREETINGS);
+    std::fs::create_dir_all(dir).map_err(|e| AudioError::Io(dir.to_owned(), e))?;
+    for ext in EXTENSIONS {
+        let old = dir.join(format!("{name}.{ext}"));
+        if old.is_file() {
+            std::fs::remove_file(&old).map_err(|e| AudioError::Io(old, e))?;
+        }
+    }
+    let path = dir.join(format!("{name}.{}", Codec::Pcmu.ext()));
+    std::fs::write(&path, Codec::Pcmu.encode_all(samples)).map_err(|e| AudioError::Io(path, e))?;
+    if let Ok(mut c) = CACHE.write() {
+        c.retain(|(n, _), _| n != name);
+    }
+    Ok(())
+}
+
+/// Names of the greetings on disk, and whether each was uploaded.
+pub fn list() -> Vec<(String, bool)> {
+    let mut names: Vec<(String, bool)> = vec![];
+    for (dir, uploaded) in [(GREETINGS, true), (ASSETS, false)] {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for path in entries.flatten().map(|e| e.path()).filter(|p| p.is_file()) {
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+            let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if EXTENSIONS.contains(&ext) && !names.iter().any(|(n, _)| n == name) {
+                names.push((name.to_owned(), uploaded));
+            }
+        }
+    }
+    names.sort();
+    names
+}
+
+/// Check greeting `name` loads for every codec, filling the cache.
+pub fn validate(name: &str, codecs: &[Codec]) -> Result<(), AudioError> {
+    codecs.iter().try_for_each(|c| load(name, *c).map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_au() {
+        // 16 bit stereo at 16 kHz, left and right averaged
+        let mut au = b".snd".to_vec();
+        for w in [24u32, 8, 3, 16000, 2] {
+            au.extend(w.to_be_bytes());
+        }
+        au.extend([0x10, 0x00, 0x30, 0x00, 0xff, 0x00, 0xff, 0x00]);
+        let pcm = decode_au(&au).unwrap();
+        assert_eq!(pcm.rate, 16000);
+        assert_eq!(pcm.samples, vec![0x2000, -256]);
+
+        assert!(decode_au(b"RIFF").is_err());
+        au[15] = 5;
+        assert_eq!(decode_au(&au).unwrap_err(), "unsupported AU encoding 5");
+    }
+
+    /// 24 bit mono at 16 kHz
+    fn wav() -> Vec<u8> {
+        let samples = (0..1600).map(|i| if i % 32 < 16 { 0x100000 } else { -0x100000 });
+        let data = samples.flat_map(|s: i32| s.to_le_bytes()[..3].to_vec()).collect::<Vec<_>>();
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(16000u32.to_le_bytes());
+        wav.extend((16000u32 * 3).to_le_bytes());
+        wav.extend(3u16.to_le_bytes());
+        wav.extend(24u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+
+    #[test]
+    fn test_decode_wav() {
+        let path = std::env::temp_dir().join(format!("greeting-{}.wav", std::process::id()));
+        std::fs::write(&path, wav()).unwrap();
+
+        let pcm = decode(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pcm.rate, 16000);
+        assert_eq!(pcm.samples.len(), 1600);
+        assert_eq!(pcm.samples[0], 0x1000);
+        assert_eq!(resample(&pcm.samples, pcm.rate).len(), 800);
+
+        assert!(matches!(
+            decode(Path::new("/nonexistent/greeting.wav")),
+            Err(AudioError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        // sizes left at the placeholder a synthesizer writes to stdout
+        let mut wav = wav();
+        wav[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        wav[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+        let pcm = decode_bytes(wav, "wav").unwrap();
+        assert_eq!(pcm.samples.len(), 1600);
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("after_hours-2"));
+        assert!(!valid_name("../database/voicemail"));
+        assert!(!valid_name("greeting.wav"));
+        assert!(!valid_name(""));
+    }
+
+    #[test]
+    fn test_resample() {
+        let ramp = (0..480).map(|i| i as i16).collect::<Vec<_>>();
+        let down = resample(&ramp, 48000);
+        assert_eq!(down.len(), 80);
+        // each output is the mean of six inputs
+        assert_eq!(&down[..3], &[3, 9, 15]);
+
+        let up = resample(&[0, 100, 200, 300], 4000);
+        assert_eq!(up, vec![0, 50, 100, 150, 200, 250, 300, 300]);
+        assert_eq!(resample(&ramp, 8000), ramp);
+    }
+}
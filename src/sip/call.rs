@@ -0,0 +1,101 @@
+use rsipstack::dialog::DialogId;
+use serde::Serialize;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
+use tokio::sync::{RwLock, broadcast};
+
+/// Events raised by the media of a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEvent {
+    Dtmf(char),
+}
+
+/// State of a single call, keyed by its dialog id.
+#[derive(Debug, Clone)]
+pub struct CallContext {
+    pub caller: String,
+    pub mailbox: String,
+    pub payload_type: Option<u8>,
+    pub rtp_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
+    pub record_id: Option<i64>,
+    pub started: Instant,
+    pub answered: Option<Instant>,
+    pub events: broadcast::Sender<CallEvent>,
+}
+
+impl CallContext {
+    pub fn new(caller: String) -> Self {
+        let (events, _) = broadcast::channel(16);
+        Self {
+            caller,
+            mailbox: String::new(),
+            payload_type: None,
+            rtp_addr: None,
+            peer_addr: None,
+            record_id: None,
+            started: Instant::now(),
+            answered: None,
+            events,
+        }
+    }
+}
+
+/// Snapshot of a call for the web api.
+#[derive(Debug, Serialize)]
+pub struct CallInfo {
+    pub id: String,
+    pub caller: String,
+    pub mailbox: String,
+    pub payload_type: Option<u8>,
+    pub rtp_addr: Option<String>,
+    pub peer_addr: Option<String>,
+    pub record_id: Option<i64>,
+    pub elapsed: u64,
+    pub answered: bool,
+}
+
+/// Active calls shared between the dialog loop and the web server.
+#[derive(Debug, Clone, Default)]
+pub struct Calls(Arc<RwLock<HashMap<DialogId, CallContext>>>);
+
+impl Calls {
+    pub async fn insert(&self, id: DialogId, ctx: CallContext) {
+        self.0.write().await.insert(id, ctx);
+    }
+
+    pub async fn get(&self, id: &DialogId) -> Option<CallContext> {
+        self.0.read().await.get(id).cloned()
+    }
+
+    pub async fn update<F>(&self, id: &DialogId, f: F)
+    where
+        F: FnOnce(&mut CallContext),
+    {
+        if let Some(ctx) = self.0.write().await.get_mut(id) {
+            f(ctx);
+        }
+    }
+
+    pub async fn remove(&self, id: &DialogId) -> Option<CallContext> {
+        self.0.write().await.remove(id)
+    }
+
+    pub async fn list(&self) -> Vec<CallInfo> {
+        self.0
+            .read()
+            .await
+            .iter()
+            .map(|(id, c)| CallInfo {
+                id: id.to_string(),
+                caller: c.caller.clone(),
+                mailbox: c.mailbox.clone(),
+                payload_type: c.payload_type,
+                rtp_addr: c.rtp_addr.map(|a| a.to_string()),
+                peer_addr: c.peer_addr.map(|a| a.to_string()),
+                record_id: c.record_id,
+                elapsed: c.started.elapsed().as_secs(),
+                answered: c.answered.is_some(),
+            })
+            .collect()
+    }
+}
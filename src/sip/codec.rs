@@ -0,0 +1,112 @@
+use audio_codec_algorithms::{decode_alaw, decode_ulaw, encode_alaw, encode_ulaw};
+use clap::ValueEnum;
+use std::fmt;
+
+/// G.711 codecs we can negotiate, play and record.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    Pcmu,
+    Pcma,
+}
+
+impl Codec {
+    pub fn from_payload_type(pt: u8) -> Option<Self> {
+        match pt {
+            0 => Some(Codec::Pcmu),
+            8 => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "PCMU" => Some(Codec::Pcmu),
+            "PCMA" => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+
+    pub fn payload_type(&self) -> u8 {
+        match self {
+            Codec::Pcmu => 0,
+            Codec::Pcma => 8,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Pcmu => "PCMU",
+            Codec::Pcma => "PCMA",
+        }
+    }
+
+    /// file extension of the raw assets
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Codec::Pcmu => "pcmu",
+            Codec::Pcma => "pcma",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Codec::Pcmu => "audio/basic",
+            Codec::Pcma => "audio/x-alaw-basic",
+        }
+    }
+
+    pub fn decode(&self, b: u8) -> i16 {
+        match self {
+            Codec::Pcmu => decode_ulaw(b),
+            Codec::Pcma => decode_alaw(b),
+        }
+    }
+
+    pub fn encode(&self, s: i16) -> u8 {
+        match self {
+            Codec::Pcmu => encode_ulaw(s),
+            Codec::Pcma => encode_alaw(s),
+        }
+    }
+
+    pub fn decode_all(&self, data: &[u8]) -> Vec<i16> {
+        data.iter().map(|b| self.decode(*b)).collect()
+    }
+
+    pub fn encode_all(&self, pcm: &[i16]) -> Vec<u8> {
+        pcm.iter().map(|s| self.encode(*s)).collect()
+    }
+
+    /// transcode G.711 bytes of `from` into this codec
+    pub fn transcode(&self, from: Codec, data: &[u8]) -> Vec<u8> {
+        if *self == from {
+            return data.to_vec();
+        }
+        data.iter().map(|b| self.encode(from.decode(*b))).collect()
+    }
+
+    /// value of a silent sample
+    pub fn silence(&self) -> u8 {
+        self.encode(0)
+    }
+
+    /// Sine tone at 8 kHz with 5 ms fades so it does not click.
+    pub fn tone(&self, freq: f32, ms: u32) -> Vec<u8> {
+        let len = ms as usize * 8;
+        let fade = 40.min(len / 2).max(1) as f32;
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / 8000.0;
+                let edge = (i.min(len - 1 - i) as f32 / fade).min(1.0);
+                let v = (2.0 * std::f32::consts::PI * freq * t).sin() * 8000.0 * edge;
+                self.encode(v as i16)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
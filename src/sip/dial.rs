@@ -0,0 +1,433 @@
+use crate::sip::MediaSessionOption;
+use crate::sip::latch::{MediaLatch, SharedLatch};
+use crate::sip::play_file::{bind_rtp, observe, target};
+use crate::sip::rtp::RtpPacket;
+use crate::sip::sdp::{self, Negotiated, SessionOffer, negotiate};
+use crate::sip::srtp::{MasterKey, Srtp, SrtpKeys, SrtpPolicy};
+use anyhow::{Result, anyhow, bail};
+use rsipstack::{
+    dialog::{
+        authenticate::Credential,
+        client_dialog::ClientInviteDialog,
+        dialog::{DialogState as SipDialogState, DialogStateReceiver},
+        dialog_layer::DialogLayer,
+        invitation::InviteOption,
+    },
+    transport::{SipAddr, udp::UdpConnection},
+};
+use rtp_rs::RtpPacketBuilder;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::{Mutex, RwLock, mpsc},
+};
+use tracing::info;
+
+/// finished calls are listed this long
+const KEEP: Duration = Duration::from_secs(600);
+
+/// Progress of a call back, as shown in the browser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialState {
+    /// ringing the user's own extension
+    CallingYou,
+    /// the user answered, calling the caller of the message
+    CallingBack,
+    Ringing,
+    Connected,
+    Ended,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Dial {
+    message: i64,
+    number: String,
+    state: DialState,
+    error: Option<String>,
+    started: Instant,
+}
+
+impl Dial {
+    fn done(&self) -> bool {
+        matches!(self.state, DialState::Ended | DialState::Failed)
+    }
+
+    fn info(&self, id: u64) -> DialInfo {
+        DialInfo {
+            id,
+            message: self.message,
+            number: self.number.clone(),
+            state: self.state,
+            error: self.error.clone(),
+            elapsed: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Snapshot of a call back for the web api.
+#[derive(Debug, Serialize)]
+pub struct DialInfo {
+    pub id: u64,
+    /// the message whose caller is called back
+    pub message: i64,
+    pub number: String,
+    pub state: DialState,
+    pub error: Option<String>,
+    pub elapsed: u64,
+}
+
+#[derive(Debug)]
+pub struct DialRequest {
+    pub id: u64,
+    /// mailbox of the message, whose account places the calls
+    pub mailbox: String,
+    pub number: String,
+}
+
+/// Call backs asked for by the web server and their progress.
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    requests: mpsc::UnboundedSender<DialRequest>,
+    dials: Arc<RwLock<HashMap<u64, Dial>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Dialer {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<DialRequest>) {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let dialer = Self {
+            requests,
+            dials: Default::default(),
+            next_id: Arc::new(AtomicU64::new(1)),
+        };
+        (dialer, rx)
+    }
+
+    /// Ask the SIP side to call `number` back for `message`.
+    pub async fn dial(&self, message: i64, mailbox: String, number: String) -> Result<DialInfo, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut dials = self.dials.write().await;
+        dials.retain(|_, d| !d.done() || d.started.elapsed() < KEEP);
+        let dial = Dial {
+            message,
+            number: number.clone(),
+            state: DialState::CallingYou,
+            error: None,
+            started: Instant::now(),
+        };
+        let info = dial.info(id);
+        dials.insert(id, dial);
+        self.requests
+            .send(DialRequest { id, mailbox, number })
+            .map_err(|_| "the SIP client is not running".to_owned())?;
+        Ok(info)
+    }
+
+    pub async fn update(&self, id: u64, state: DialState, error: Option<String>) {
+        if let Some(d) = self.dials.write().await.get_mut(&id) {
+            d.state = state;
+            d.error = error;
+        }
+    }
+
+    pub async fn list(&self) -> Vec<DialInfo> {
+        let mut list = self.dials.read().await.iter().map(|(id, d)| d.info(*id)).collect::<Vec<_>>();
+        list.sort_by_key(|d| d.id);
+        list
+    }
+}
+
+/// What an outgoing call needs from the account it is placed with.
+#[derive(Clone)]
+pub struct DialAccount {
+    pub mailbox: String,
+    pub server: rsip::Uri,
+    pub from: rsip::Uri,
+    pub contact: rsip::Uri,
+    pub credential: Credential,
+}
+
+#[derive(Clone)]
+pub struct DialContext {
+    pub dialog_layer: Arc<DialogLayer>,
+    pub accounts: Vec<DialAccount>,
+    pub opt: Arc<Mutex<MediaSessionOption>>,
+}
+
+impl DialContext {
+    /// The account of `mailbox`, else the first one.
+    fn account(&self, mailbox: &str) -> Option<&DialAccount> {
+        self.accounts.iter().find(|a| a.mailbox == mailbox).or(self.accounts.first())
+    }
+}
+
+/// `target` as a URI, numbers and bare users going through `server`
+/// over its scheme and transport.
+fn uri(target: &str, server: &rsip::Uri) -> Result<rsip::Uri> {
+    if target.starts_with("sip:") || target.starts_with("sips:") {
+        return Ok(rsip::Uri::try_from(target)?);
+    }
+    if target.contains('@') {
+        return Ok(rsip::Uri::try_from(format!("sip:{target}"))?);
+    }
+    Ok(rsip::Uri {
+        auth: Some(rsip::Auth { user: target.to_owned(), password: None }),
+        ..server.clone()
+    })
+}
+
+/// One side of the bridge.
+struct Leg {
+    dialog: ClientInviteDialog,
+    conn: UdpConnection,
+    media: Negotiated,
+    latch: SharedLatch,
+    srtp: Srtp,
+    ssrc: u32,
+    states: DialogStateReceiver,
+}
+
+/// Place a call and wait for it to be answered, reporting `ringing`
+/// on a provisional response.
+async fn invite(
+    ctx: &DialContext,
+    account: &DialAccount,
+    dialer: &Dialer,
+    id: u64,
+    callee: rsip::Uri,
+    ringing: Option<DialState>,
+) -> Result<Leg> {
+    let (conn, _) = bind_rtp(ctx.opt.clone()).await?;
+    let local: SocketAddr = conn.get_addr().addr.to_owned().try_into()?;
+    let ssrc = rand::random::<u32>();
+    let (codecs, latching, policy) = {
+        let opt = ctx.opt.lock().await;
+        (opt.codecs.clone(), opt.latching, opt.srtp)
+    };
+    // offering RTP/SAVP to a phone without SRTP fails the call, so
+    // only when it is required
+    let key = (policy == SrtpPolicy::Mandatory).then(MasterKey::random);
+    let option = InviteOption {
+        caller: account.from.clone(),
+        callee: callee.clone(),
+        destination: None,
+        content_type: None,
+        offer: Some(sdp::offer(local, &codecs, ssrc, key.as_ref()).into_bytes()),
+        contact: account.contact.clone(),
+        credential: Some(account.credential.clone()),
+        headers: None,
+    };
+    let (state_sender, mut states) = mpsc::unbounded_channel();
+    let invite = ctx.dialog_layer.do_invite(option, state_sender);
+    tokio::pin!(invite);
+    let (dialog, resp) = loop {
+        select! {
+            r = &mut invite => break r?,
+            Some(state) = states.recv() => {
+                if let SipDialogState::Early(..) = state
+                    && let Some(ringing) = ringing
+                {
+                    dialer.update(id, ringing, None).await;
+                }
+            }
+        }
+    };
+    let resp = resp.ok_or_else(|| anyhow!("no answer from {callee}"))?;
+    if resp.status_code != rsip::StatusCode::OK {
+        bail!("{callee}: {}", resp.status_code);
+    }
+    let media = match SessionOffer::parse(&String::from_utf8_lossy(&resp.body))
+        .and_then(|answer| negotiate(&answer, &codecs, policy))
+    {
+        // the answer is keyed to what we offered
+        Ok(media) => Negotiated {
+            srtp: media.srtp.zip(key).map(|(keys, local)| SrtpKeys { local, ..keys }),
+            ..media
+        },
+        Err(e) => {
+            dialog.bye().await.ok();
+            bail!("{callee}: {e}");
+        }
+    };
+    info!("call back {id}: {callee} answered, peer {} {}", media.peer, media.codec);
+    Ok(Leg {
+        dialog,
+        conn,
+        media,
+        latch: MediaLatch::shared(latching, media.peer),
+        srtp: Srtp::new(media.srtp),
+        ssrc,
+        states,
+    })
+}
+
+/// Payload type and payload for the other leg, None when it has no
+/// equivalent.
+fn translate(pt: u8, payload: &[u8], from: &Negotiated, to: &Negotiated) -> Option<(u8, Vec<u8>)> {
+    if pt == from.codec.payload_type() {
+        return Some((to.codec.payload_type(), to.codec.transcode(from.codec, payload)));
+    }
+    if from.dtmf == Some(pt) {
+        return to.dtmf.map(|dtmf| (dtmf, payload.to_vec()));
+    }
+    None
+}
+
+async fn relay(buf: &[u8], from: &SipAddr, src: &Leg, dst: &Leg) {
+    let Some(buf) = src.srtp.unprotect_rtp(buf) else {
+        return;
+    };
+    let Ok(rtp) = RtpPacket::parse(&buf) else {
+        return;
+    };
+    if !src.media.accepts(rtp.payload_type) {
+        return;
+    }
+    observe(&src.latch, from);
+    let Some((pt, payload)) = translate(rtp.payload_type, rtp.payload, &src.media, &dst.media) else {
+        return;
+    };
+    let packet = match RtpPacketBuilder::new()
+        .payload_type(pt)
+        .ssrc(dst.ssrc)
+        .sequence(rtp.sequence.into())
+        .timestamp(rtp.timestamp)
+        .payload(&payload)
+        .build()
+    {
+        Ok(p) => p,
+        Err(e) => {
+            info!("Failed to build RTP packet: {:?}", e);
+            return;
+        }
+    };
+    let Some(packet) = dst.srtp.protect_rtp(&packet) else {
+        return;
+    };
+    if let Err(e) = dst.conn.send_raw(&packet, &target(&dst.latch, dst.media.peer)).await {
+        info!("Failed to relay RTP: {:?}", e);
+    }
+}
+
+fn hung_up(state: Option<SipDialogState>) -> bool {
+    matches!(state, None | Some(SipDialogState::Terminated(..)))
+}
+
+/// Relay media both ways until either side hangs up.
+async fn bridge(a: &mut Leg, b: &mut Leg) {
+    let (mut abuf, mut bbuf) = (vec![0; 1500], vec![0; 1500]);
+    loop {
+        select! {
+            r = a.conn.recv_raw(&mut abuf) => match r {
+                Ok((len, from)) => relay(&abuf[..len], &from, a, b).await,
+                Err(e) => {
+                    info!("Failed to receive RTP: {:?}", e);
+                    break;
+                }
+            },
+            r = b.conn.recv_raw(&mut bbuf) => match r {
+                Ok((len, from)) => relay(&bbuf[..len], &from, b, a).await,
+                Err(e) => {
+                    info!("Failed to receive RTP: {:?}", e);
+                    break;
+                }
+            },
+            s = a.states.recv() => if hung_up(s) { break },
+            s = b.states.recv() => if hung_up(s) { break },
+        }
+    }
+}
+
+/// Ring the user's extension, then the caller of the message, and
+/// connect the two.
+async fn call_back(ctx: &DialContext, dialer: &Dialer, id: u64, mailbox: &str, number: &str) -> Result<()> {
+    let account = ctx.account(mailbox).ok_or_else(|| anyhow!("no SIP server to call through"))?;
+    let extension = ctx.opt.lock().await.extension.clone();
+    let extension = extension.ok_or_else(|| anyhow!("no --extension to ring first"))?;
+    let (you, them) = (uri(&extension, &account.server)?, uri(number, &account.server)?);
+    info!("call back {id}: {you} then {them} from {}", account.from);
+
+    let mut a = invite(ctx, account, dialer, id, you, None).await?;
+    dialer.update(id, DialState::CallingBack, None).await;
+    let mut b = match invite(ctx, account, dialer, id, them, Some(DialState::Ringing)).await {
+        Ok(b) => b,
+        Err(e) => {
+            a.dialog.bye().await.ok();
+            ctx.dialog_layer.remove_dialog(&a.dialog.id());
+            return Err(e);
+        }
+    };
+    dialer.update(id, DialState::Connected, None).await;
+    bridge(&mut a, &mut b).await;
+    info!("call back {id} finished");
+    for leg in [&a, &b] {
+        // the side that hung up is already gone
+        leg.dialog.bye().await.ok();
+        ctx.dialog_layer.remove_dialog(&leg.dialog.id());
+    }
+    dialer.update(id, DialState::Ended, None).await;
+    Ok(())
+}
+
+pub async fn process_dials(
+    ctx: DialContext,
+    dialer: Dialer,
+    mut requests: mpsc::UnboundedReceiver<DialRequest>,
+) -> Result<()> {
+    while let Some(req) = requests.recv().await {
+        let (ctx, dialer) = (ctx.clone(), dialer.clone());
+        tokio::spawn(async move {
+            if let Err(e) = call_back(&ctx, &dialer, req.id, &req.mailbox, &req.number).await {
+                info!("call back {} failed: {e}", req.id);
+                dialer.update(req.id, DialState::Failed, Some(e.to_string())).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sip::codec::Codec;
+
+    fn media(codecs: &[Codec], dtmf: bool) -> Negotiated {
+        let mut sdp = sdp::offer("192.0.2.1:4000".parse().unwrap(), codecs, 1, None);
+        if !dtmf {
+            sdp = sdp.replace(" 101\r\n", "\r\n");
+        }
+        negotiate(&SessionOffer::parse(&sdp).unwrap(), codecs, SrtpPolicy::Optional).unwrap()
+    }
+
+    #[test]
+    fn test_translate() {
+        let pcmu = media(&[Codec::Pcmu], true);
+        let pcma = media(&[Codec::Pcma], false);
+        let silence = vec![Codec::Pcmu.silence(); 160];
+
+        let (pt, payload) = translate(0, &silence, &pcmu, &pcma).unwrap();
+        assert_eq!(pt, 8);
+        assert_eq!(payload, vec![Codec::Pcma.silence(); 160]);
+        // telephone-event only where both sides have it
+        assert!(translate(101, &[1, 0x80, 0, 160], &pcmu, &pcma).is_none());
+        assert_eq!(translate(101, &[1], &pcmu, &pcmu).unwrap().0, 101);
+        assert!(translate(18, &silence, &pcmu, &pcma).is_none());
+
+        let server = rsip::Uri::try_from("sip:sip.example.com").unwrap();
+        assert_eq!(uri("0312345678", &server).unwrap().to_string(), "sip:0312345678@sip.example.com");
+        assert_eq!(uri("100@pbx.local", &server).unwrap().to_string(), "sip:100@pbx.local");
+        let tls = rsip::Uri::try_from("sips:sip.example.com:5061").unwrap();
+        assert_eq!(uri("100", &tls).unwrap().to_string(), "sips:100@sip.example.com:5061");
+    }
+}
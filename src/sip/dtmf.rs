@@ -0,0 +1,219 @@
+/// RFC 4733 event codes 0-15 as keys.
+const KEYS: &[u8; 16] = b"0123456789*#ABCD";
+
+pub fn event_key(event: u8) -> Option<char> {
+    KEYS.get(event as usize).map(|k| *k as char)
+}
+
+/// Payload of a telephone-event packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelephoneEvent {
+    pub event: u8,
+    pub end: bool,
+}
+
+impl TelephoneEvent {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        // volume and duration follow, keys are reported on the end bit alone
+        if payload.len() < 4 {
+            return None;
+        }
+        Some(TelephoneEvent {
+            event: payload[0],
+            end: payload[1] & 0x80 != 0,
+        })
+    }
+}
+
+/// Turns the packets of RFC 4733 events into single key presses.
+///
+/// All packets of one event share the RTP timestamp and the end packet
+/// is usually sent three times, so a key is reported once per timestamp:
+/// on the first end packet, or when the next event starts if every end
+/// packet was lost.
+#[derive(Debug, Default)]
+pub struct DtmfDecoder {
+    current: Option<(u32, u8)>,
+    reported: bool,
+}
+
+impl DtmfDecoder {
+    pub fn push(&mut self, timestamp: u32, payload: &[u8]) -> Option<char> {
+        let ev = TelephoneEvent::parse(payload)?;
+        let mut key = None;
+        match self.current {
+            Some((ts, _)) if ts == timestamp => {}
+            Some((_, event)) => {
+                if !self.reported {
+                    key = event_key(event);
+                }
+                self.current = Some((timestamp, ev.event));
+                self.reported = false;
+            }
+            None => {
+                self.current = Some((timestamp, ev.event));
+                self.reported = false;
+            }
+        }
+        if ev.end && !self.reported && key.is_none() {
+            self.reported = true;
+            key = event_key(ev.event);
+        }
+        key
+    }
+}
+
+const ROWS: [f32; 4] = [697.0, 770.0, 852.0, 941.0];
+const COLS: [f32; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+const PAD: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+/// samples per Goertzel block at 8 kHz
+const BLOCK: usize = 205;
+/// minimum mean square of a block to look for tones
+const MIN_ENERGY: f32 = 40_000.0;
+/// 8 dB
+const TWIST: f32 = 6.3;
+/// share of the block energy the two tones must carry
+const TONE_SHARE: f32 = 0.6;
+
+fn goertzel(samples: &[f32], freq: f32) -> f32 {
+    let coeff = 2.0 * (2.0 * std::f32::consts::PI * freq / 8000.0).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for x in samples {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    s1 * s1 + s2 * s2 - coeff * s1 * s2
+}
+
+fn strongest(powers: &[f32; 4]) -> Option<(usize, f32)> {
+    let (i, max) = powers
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    // the other tones of the group must be well below the peak
+    let clear = powers
+        .iter()
+        .enumerate()
+        .all(|(j, p)| j == i || p * TWIST < max);
+    clear.then_some((i, max))
+}
+
+/// Goertzel detector for DTMF sent as audio.
+///
+/// A key is reported once it was seen in two consecutive blocks
+/// (about 50 ms), and not again until the tone stops.
+#[derive(Debug, Default)]
+pub struct ToneDetector {
+    buf: Vec<f32>,
+    candidate: Option<char>,
+    reported: Option<char>,
+}
+
+impl ToneDetector {
+    pub fn push(&mut self, samples: &[i16]) -> Option<char> {
+        let mut key = None;
+        for s in samples {
+            self.buf.push(*s as f32);
+            if self.buf.len() == BLOCK {
+                let found = self.detect();
+                self.buf.clear();
+                if found.is_some() && found == self.candidate && found != self.reported {
+                    self.reported = found;
+                    key = found;
+                }
+                if found.is_none() {
+                    self.reported = None;
+                }
+                self.candidate = found;
+            }
+        }
+        key
+    }
+
+    /// whether the last block held a tone
+    pub fn in_tone(&self) -> bool {
+        self.candidate.is_some()
+    }
+
+    fn detect(&self) -> Option<char> {
+        let energy = self.buf.iter().map(|x| x * x).sum::<f32>();
+        if energy / (BLOCK as f32) < MIN_ENERGY {
+            return None;
+        }
+        let rows = ROWS.map(|f| goertzel(&self.buf, f));
+        let cols = COLS.map(|f| goertzel(&self.buf, f));
+        let (r, row) = strongest(&rows)?;
+        let (c, col) = strongest(&cols)?;
+        if row > col * TWIST || col > row * TWIST {
+            return None;
+        }
+        // a pure tone of amplitude a has power (a * N / 2)^2 and energy a^2 * N / 2
+        if (row + col) / (energy * BLOCK as f32 / 2.0) < TONE_SHARE {
+            return None;
+        }
+        Some(PAD[r][c])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(event: u8, end: bool, duration: u16) -> Vec<u8> {
+        let d = duration.to_be_bytes();
+        vec![event, if end { 0x8a } else { 0x0a }, d[0], d[1]]
+    }
+
+    #[test]
+    fn test_dtmf_decoder() {
+        let mut dec = DtmfDecoder::default();
+        // '5' with the end packet repeated three times
+        assert_eq!(dec.push(1000, &packet(5, false, 160)), None);
+        assert_eq!(dec.push(1000, &packet(5, false, 320)), None);
+        assert_eq!(dec.push(1000, &packet(5, true, 480)), Some('5'));
+        assert_eq!(dec.push(1000, &packet(5, true, 480)), None);
+        assert_eq!(dec.push(1000, &packet(5, true, 480)), None);
+        // '#' whose end packets were lost, reported when '1' starts
+        assert_eq!(dec.push(3000, &packet(11, false, 160)), None);
+        assert_eq!(dec.push(5000, &packet(1, false, 160)), Some('#'));
+        assert_eq!(dec.push(5000, &packet(1, true, 320)), Some('1'));
+    }
+
+    fn tone(row: f32, col: f32, ms: usize) -> Vec<i16> {
+        (0..ms * 8)
+            .map(|i| {
+                let t = i as f32 / 8000.0;
+                let v = (2.0 * std::f32::consts::PI * row * t).sin()
+                    + (2.0 * std::f32::consts::PI * col * t).sin();
+                (v * 6000.0) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tone_detector() {
+        let mut det = ToneDetector::default();
+        let mut audio = tone(941.0, 1477.0, 100);
+        audio.extend(vec![0; 400]);
+        audio.extend(tone(770.0, 1336.0, 100));
+        audio.extend(tone(770.0, 1336.0, 100));
+        let keys = audio
+            .chunks(160)
+            .filter_map(|c| det.push(c))
+            .collect::<String>();
+        assert_eq!(keys, "#5");
+        assert!(det.in_tone());
+
+        let speech = (0..8000)
+            .map(|i| ((i as f32 / 8000.0 * 2.0 * std::f32::consts::PI * 440.0).sin() * 8000.0) as i16)
+            .collect::<Vec<_>>();
+        assert!(speech.chunks(160).all(|c| det.push(c).is_none()));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::sip::{audio_file, codec::Codec};
+use crate::text_to_speech;
+use crate::web::db::GreetingRule;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+/// The caller a greeting is chosen for.
+#[derive(Debug, Clone, Copy)]
+pub struct CallerInfo<'a> {
+    pub mailbox: &'a str,
+    pub caller: &'a str,
+    /// the caller is in the contacts table
+    pub known: bool,
+}
+
+/// Check a greeting can be played, a file for every codec or the
+/// text of a `tts:` greeting.
+pub fn validate(greeting: &str, codecs: &[Codec]) -> Result<(), String> {
+    match text_to_speech::text(greeting) {
+        Some(text) => text_to_speech::check(text),
+        None => audio_file::validate(greeting, codecs).map_err(|e| e.to_string()),
+    }
+}
+
+/// `validate` for a greeting stored from the web UI, where a file is only
+/// taken by its name in the greetings or assets directory, never a path.
+pub fn validate_stored(greeting: &str, codecs: &[Codec]) -> Result<(), String> {
+    if text_to_speech::text(greeting).is_none() && !audio_file::valid_name(greeting) {
+        return Err(audio_file::AudioError::InvalidName(greeting.to_owned()).to_string());
+    }
+    validate(greeting, codecs)
+}
+
+/// Parsed time conditions of a rule.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// indexed from Monday
+    days: Option<[bool; 7]>,
+    time: Option<(NaiveTime, NaiveTime)>,
+    dates: Option<(NaiveDate, NaiveDate)>,
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    let day = |d: &str| {
+        d.trim()
+            .parse::<Weekday>()
+            .map(|d| d.num_days_from_monday() as usize)
+            .map_err(|_| format!("invalid day '{d}'"))
+    };
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            // ranges may wrap, as in fri-mon
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                let len = (to + 7 - from) % 7;
+                (0..=len).for_each(|i| days[(from + i) % 7] = true);
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|_| format!("invalid time '{s}', expected HH:MM"))
+}
+
+fn parse_date(s: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("invalid date '{s}', expected YYYY-MM-DD"))
+}
+
+impl Schedule {
+    pub fn parse(rule: &GreetingRule) -> Result<Self, String> {
+        let days = rule.days.as_deref().map(parse_days).transpose()?;
+        let time = match (&rule.start_time, &rule.end_time) {
+            (None, None) => None,
+            (Some(start), Some(end)) => Some((parse_time(start)?, parse_time(end)?)),
+            _ => return Err("start_time and end_time must be set together".into()),
+        };
+        let dates = match (&rule.start_date, &rule.end_date) {
+            (None, None) => None,
+            (start, end) => Some((
+                start.as_deref().map(parse_date).transpose()?.unwrap_or(NaiveDate::MIN),
+                end.as_deref().map(parse_date).transpose()?.unwrap_or(NaiveDate::MAX),
+            )),
+        };
+        Ok(Schedule { days, time, dates })
+    }
+
+    fn matches(&self, now: NaiveDateTime) -> bool {
+        let day = now.weekday().num_days_from_monday() as usize;
+        let time = now.time();
+        self.days.is_none_or(|d| d[day])
+            && self.time.is_none_or(|(start, end)| {
+                if start <= end {
+                    start <= time && time < end
+                } else {
+                    // after hours, 18:00-09:00
+                    start <= time || time < end
+                }
+            })
+            && self.dates.is_none_or(|(start, end)| (start..=end).contains(&now.date()))
+    }
+}
+
+fn applies(rule: &GreetingRule, call: &CallerInfo, now: NaiveDateTime) -> bool {
+    rule.mailbox.as_deref().is_none_or(|m| m == call.mailbox)
+        && rule.caller.as_deref().is_none_or(|c| c == call.caller)
+        && rule.known.is_none_or(|k| k == call.known)
+        && Schedule::parse(rule).is_ok_and(|s| s.matches(now))
+}
+
+/// An out of office range beats a caller, which beats a time of day.
+fn specificity(rule: &GreetingRule) -> u8 {
+    let set = |o: bool, w: u8| if o { w } else { 0 };
+    set(rule.start_date.is_some() || rule.end_date.is_some(), 8)
+        + set(rule.caller.is_some(), 4)
+        + set(rule.known.is_some(), 2)
+        + set(rule.days.is_some() || rule.start_time.is_some(), 1)
+}
+
+/// The rule deciding the greeting, by priority and then specificity.
+/// Rules come in id order, so the oldest wins a tie.
+pub fn select<'a>(
+    rules: &'a [GreetingRule],
+    call: &CallerInfo,
+    now: NaiveDateTime,
+) -> Option<&'a GreetingRule> {
+    rules
+        .iter()
+        .filter(|r| applies(r, call, now))
+        .rev()
+        .max_by_key(|r| (r.priority.unwrap_or_default(), specificity(r)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(greeting: &str) -> GreetingRule {
+        GreetingRule {
+            greeting: greeting.into(),
+            ..Default::default()
+        }
+    }
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut r = rule("after_hours");
+        r.days = Some("mon-fri".into());
+        r.start_time = Some("18:00".into());
+        r.end_time = Some("09:00".into());
+        let s = Schedule::parse(&r).unwrap();
+        // 2026-10-16 is a Friday
+        assert!(s.matches(at("2026-10-16 20:00")));
+        assert!(s.matches(at("2026-10-16 08:59")));
+        assert!(!s.matches(at("2026-10-16 12:00")));
+        assert!(!s.matches(at("2026-10-17 20:00")));
+
+        assert_eq!(parse_days("fri-mon").unwrap(), [true, false, false, false, true, true, true]);
+        r.days = Some("funday".into());
+        assert!(Schedule::parse(&r).is_err());
+        r.days = None;
+        r.end_time = None;
+        assert!(Schedule::parse(&r).is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut business = rule("business");
+        business.days = Some("mon-fri".into());
+        business.start_time = Some("09:00".into());
+        business.end_time = Some("18:00".into());
+        let mut friends = rule("friends");
+        friends.known = Some(true);
+        let mut boss = rule("boss");
+        boss.caller = Some("0312345678".into());
+        let mut away = rule("away");
+        away.start_date = Some("2026-12-24".into());
+        away.end_date = Some("2027-01-03".into());
+        let mut other = rule("other");
+        other.mailbox = Some("sales".into());
+        let rules = [business, friends, boss, away, other];
+
+        let call = |caller, known| CallerInfo { mailbox: "100", caller, known };
+        let pick = |call: CallerInfo, now| select(&rules, &call, at(now)).map(|r| r.greeting.as_str());
+        assert_eq!(pick(call("0000", false), "2026-10-16 10:00"), Some("business"));
+        assert_eq!(pick(call("0000", true), "2026-10-16 10:00"), Some("friends"));
+        assert_eq!(pick(call("0312345678", true), "2026-10-16 10:00"), Some("boss"));
+        assert_eq!(pick(call("0312345678", true), "2026-12-25 10:00"), Some("away"));
+        assert_eq!(pick(call("0000", false), "2026-10-17 10:00"), None);
+
+        let mut rules = rules.to_vec();
+        rules[0].priority = Some(1);
+        assert_eq!(
+            select(&rules, &call("0312345678", true), at("2026-12-25 10:00")).unwrap().greeting,
+            "business"
+        );
+    }
+
+    #[test]
+    fn test_validate_stored() {
+        assert!(validate_stored("/etc/passwd.wav", &[Codec::Pcmu]).unwrap_err().contains("invalid greeting name"));
+        assert!(validate_stored("../greeting", &[Codec::Pcmu]).is_err());
+        assert!(validate_stored("tts:Leave a message.", &[Codec::Pcmu]).is_ok());
+    }
+}
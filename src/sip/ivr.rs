@@ -0,0 +1,442 @@
+use crate::sip::audio_file;
+use crate::sip::dtmf::{DtmfDecoder, ToneDetector};
+use crate::sip::jitter::{Frame, JitterBuffer};
+use crate::sip::latch::SharedLatch;
+use crate::sip::codec::Codec;
+use crate::sip::play_file::{RtpStream, observe};
+use crate::sip::rtp::RtpReceiver;
+use crate::sip::sdp::Negotiated;
+use crate::sip::srtp::Srtp;
+use crate::text_to_speech::{self, TtsEngine};
+use crate::utils::trim_null_bytes;
+use crate::web::db::{DataType, Mailbox, Pool, Queries, execute};
+use rsipstack::transport::udp::UdpConnection;
+use std::time::Duration;
+use tokio::{
+    select,
+    time::{Instant, timeout, timeout_at},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// name a greeting recorded over the phone is saved as, followed by
+/// the mailbox
+const PHONE_GREETING: &str = "phone";
+const PIN_ATTEMPTS: usize = 3;
+/// time allowed for each key
+const KEY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_GREETING: Duration = Duration::from_secs(60);
+/// recordings shorter than this, 1 s, are dropped
+const MIN_GREETING: usize = 8000;
+const BEEP_FREQ: f32 = 1000.0;
+const BEEP_MS: u32 = 300;
+
+/// Keys and audio from the caller, RFC 4733 events as well as in-band
+/// tones.
+pub struct Keypad {
+    conn: UdpConnection,
+    media: Negotiated,
+    latch: SharedLatch,
+    srtp: Srtp,
+    rx: RtpReceiver,
+    dtmf: DtmfDecoder,
+    tones: Option<ToneDetector>,
+    jitter: JitterBuffer,
+    buf: Vec<u8>,
+}
+
+impl Keypad {
+    pub fn new(conn: UdpConnection, media: Negotiated, latch: SharedLatch, srtp: Srtp, inband_dtmf: bool) -> Self {
+        Self {
+            conn,
+            media,
+            latch,
+            srtp,
+            rx: RtpReceiver::default(),
+            dtmf: DtmfDecoder::default(),
+            tones: inband_dtmf.then(ToneDetector::default),
+            jitter: JitterBuffer::new(media.codec),
+            buf: vec![0; 1500],
+        }
+    }
+
+    /// Read one packet, None when the socket fails.
+    async fn recv(&mut self) -> Option<(Vec<Frame>, Option<char>)> {
+        let (len, from) = self
+            .conn
+            .recv_raw(&mut self.buf)
+            .await
+            .inspect_err(|e| info!("Failed to receive RTP: {:?}", e))
+            .ok()?;
+        let Some(packet) = self.srtp.unprotect_rtp(&self.buf[..len]) else {
+            return Some((vec![], None));
+        };
+        let Some(rtp) = self.rx.accept(&packet) else {
+            return Some((vec![], None));
+        };
+        if self.media.accepts(rtp.payload_type) {
+            observe(&self.latch, &from);
+        }
+        if self.media.dtmf == Some(rtp.payload_type) {
+            self.jitter.skip(rtp.sequence);
+            return Some((vec![], self.dtmf.push(rtp.timestamp, rtp.payload)));
+        }
+        if rtp.payload_type != self.media.codec.payload_type() {
+            return Some((vec![], None));
+        }
+        let frames = self.jitter.push(rtp.sequence, rtp.timestamp, rtp.payload);
+        let codec = self.media.codec;
+        let key = self.tones.as_mut().and_then(|t| {
+            frames.iter().fold(None, |key, f| t.push(&codec.decode_all(&f.payload)).or(key))
+        });
+        Some((frames, key))
+    }
+
+    /// Drop what arrived while a prompt played.
+    async fn discard(&mut self) {
+        while let Ok(Some(_)) = timeout(Duration::from_millis(1), self.recv()).await {}
+        self.jitter.restart();
+    }
+
+    /// The next key, None when the call is gone.
+    async fn next_key(&mut self) -> Option<char> {
+        loop {
+            let (_, key) = self.recv().await?;
+            if let Some(key) = key {
+                info!("DTMF: {key}");
+                return Some(key);
+            }
+        }
+    }
+
+    /// The next key, None on timeout or when the call is gone.
+    pub async fn key(&mut self, wait: Duration) -> Option<char> {
+        timeout(wait, self.next_key()).await.ok().flatten()
+    }
+
+    /// Audio until '#' or `max`, decoded to 8 kHz.
+    pub async fn record(&mut self, max: Duration) -> Vec<i16> {
+        let codec = self.media.codec;
+        let deadline = Instant::now() + max;
+        let mut samples = vec![];
+        while let Ok(Some((frames, key))) = timeout_at(deadline, self.recv()).await {
+            frames.iter().for_each(|f| samples.extend(codec.decode_all(&f.payload)));
+            if key == Some('#') {
+                break;
+            }
+        }
+        samples
+    }
+}
+
+/// Settings of the phone menu for one call.
+#[derive(Debug, Clone)]
+pub struct MenuOption {
+    /// the mailbox called
+    pub mailbox: String,
+    /// PIN of mailboxes without one of their own
+    pub pin: Option<String>,
+    pub inband_dtmf: bool,
+    pub tts: TtsEngine,
+    pub voice: String,
+}
+
+/// A message as announced over the phone.
+struct Message {
+    id: i64,
+    /// the contact name or number
+    caller: String,
+    heard: bool,
+}
+
+/// Read digits one by one rather than as a number.
+fn spell(caller: &str) -> String {
+    match caller.chars().all(|c| c.is_ascii_digit()) {
+        true => caller.chars().map(String::from).collect::<Vec<_>>().join(" "),
+        false => caller.to_owned(),
+    }
+}
+
+fn count(n: usize, what: &str) -> String {
+    match n {
+        0 => format!("no {what}s"),
+        1 => format!("1 {what}"),
+        n => format!("{n} {what}s"),
+    }
+}
+
+/// The menu behind '*' during the greeting, spoken with the greeting
+/// synthesizer.
+struct Menu<'a> {
+    pool: &'a Pool,
+    keypad: Keypad,
+    stream: RtpStream,
+    opt: MenuOption,
+    /// logged in to, the one called until then
+    mailbox: String,
+}
+
+impl Menu<'_> {
+    /// Play audio in the call codec, returning a key that interrupted it.
+    async fn play(&mut self, data: &[u8]) -> Option<char> {
+        let (stream, keypad) = (&mut self.stream, &mut self.keypad);
+        select! {
+            played = stream.play(data) => {
+                if let Err(e) = played {
+                    info!("Failed to play prompt: {:?}", e);
+                }
+                None
+            }
+            key = keypad.next_key() => key,
+        }
+    }
+
+    /// Speak `text`, or beep when it cannot be rendered.
+    async fn say(&mut self, text: &str) -> Option<char> {
+        let codec = self.keypad.media.codec;
+        let audio = match text_to_speech::render(self.pool, self.opt.tts, &self.opt.voice, text, true).await {
+            Ok(pcmu) => codec.transcode(Codec::Pcmu, &pcmu),
+            Err(e) => {
+                error!("Failed to render prompt '{text}': {e}");
+                codec.tone(BEEP_FREQ, BEEP_MS)
+            }
+        };
+        self.play(&audio).await
+    }
+
+    /// Speak `text` and wait for a key, which may interrupt it.
+    async fn ask(&mut self, text: &str) -> Option<char> {
+        match self.say(text).await {
+            Some(key) => Some(key),
+            None => self.keypad.key(KEY_TIMEOUT).await,
+        }
+    }
+
+    /// Speak `text` and read keys up to '#'.
+    async fn ask_digits(&mut self, text: &str) -> Option<String> {
+        let mut digits = String::new();
+        let mut key = self.ask(text).await;
+        loop {
+            match key? {
+                '#' => return Some(digits),
+                k => digits.push(k),
+            }
+            key = self.keypad.key(KEY_TIMEOUT).await;
+        }
+    }
+
+    async fn settings(&self, mailbox: &str) -> Option<Mailbox> {
+        execute(self.pool, Queries::Mailbox(mailbox.to_owned()))
+            .await
+            .ok()
+            .into_iter()
+            .flatten()
+            .find_map(|r| match r {
+                DataType::Mailbox(m) => Some(m),
+                _ => None,
+            })
+    }
+
+    /// The PIN of a mailbox, its own or the default for the one called.
+    async fn pin(&self, mailbox: &str) -> Option<String> {
+        match self.settings(mailbox).await.and_then(|m| m.pin) {
+            Some(pin) => Some(pin),
+            None if mailbox == self.opt.mailbox => self.opt.pin.clone(),
+            None => None,
+        }
+    }
+
+    async fn login(&mut self) -> bool {
+        for _ in 0..PIN_ATTEMPTS {
+            let Some(mailbox) = self.ask_digits("Please enter your mailbox number, then press hash.").await else {
+                return false;
+            };
+            let Some(pin) = self.ask_digits("Please enter your PIN, then press hash.").await else {
+                return false;
+            };
+            // only hash selects the mailbox called
+            let mailbox = if mailbox.is_empty() { self.opt.mailbox.clone() } else { mailbox };
+            if self.pin(&mailbox).await.is_some_and(|p| p == pin) {
+                info!("logged in to mailbox {mailbox}");
+                self.mailbox = mailbox;
+                return true;
+            }
+            info!("wrong PIN for mailbox {mailbox}");
+            self.say("The mailbox number or PIN is incorrect.").await;
+        }
+        false
+    }
+
+    /// New messages first, then the saved ones, oldest first.
+    async fn messages(&self) -> Vec<Message> {
+        let rows = match execute(self.pool, Queries::MailboxVoicemail(self.mailbox.clone())).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Failed to list messages: {e}");
+                return vec![];
+            }
+        };
+        let mut messages = rows
+            .into_iter()
+            .filter_map(|r| match r {
+                // still being recorded when the time is unset
+                DataType::VoiceList { id, caller, time, heard, .. } if time > 0 => {
+                    Some(Message { id, caller, heard })
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| (m.heard, m.id));
+        messages
+    }
+
+    async fn play_message(&mut self, id: i64) -> Option<char> {
+        let (data, codec) = match execute(self.pool, Queries::VoiceData(id)).await {
+            Ok(rows) => match rows.into_iter().next() {
+                Some(DataType::Data { data, codec }) => (trim_null_bytes(&data), codec),
+                _ => return None,
+            },
+            Err(e) => {
+                error!("Failed to read message {id}: {e}");
+                return None;
+            }
+        };
+        let stored = Codec::from_name(&codec).unwrap_or(Codec::Pcmu);
+        let audio = self.keypad.media.codec.transcode(stored, &data);
+        self.play(&audio).await
+    }
+
+    async fn listen(&mut self) {
+        let messages = self.messages().await;
+        let mut i = 0;
+        while let Some(m) = messages.get(i) {
+            let status = if m.heard { "Saved" } else { "New" };
+            let intro = format!("{status} message {}, from {}.", i + 1, spell(&m.caller));
+            let mut key = self.say(&intro).await;
+            if key.is_none() {
+                key = self.play_message(m.id).await;
+            }
+            if key.is_none() {
+                key = self
+                    .ask("Press 5 to repeat, 6 for the next message, 7 to delete, 9 to save, or hash for the main menu.")
+                    .await;
+            }
+            match key {
+                Some('5') => continue,
+                Some('6') => {}
+                Some('7') => {
+                    info!("delete message {}", m.id);
+                    if let Err(e) = execute(self.pool, Queries::DeleteVoicemail(m.id)).await {
+                        error!("Failed to delete message {}: {e}", m.id);
+                    }
+                    self.say("Message deleted.").await;
+                }
+                Some('9') => {
+                    if let Err(e) = execute(self.pool, Queries::SetHeard(m.id, true)).await {
+                        error!("Failed to save message {}: {e}", m.id);
+                    }
+                    self.say("Message saved.").await;
+                }
+                _ => return,
+            }
+            i += 1;
+        }
+        self.say("No more messages.").await;
+    }
+
+    async fn main_menu(&mut self) {
+        loop {
+            let messages = self.messages().await;
+            let new = messages.iter().filter(|m| !m.heard).count();
+            let text = format!(
+                "You have {} and {}. Press 1 to listen to your messages, 2 to record your greeting, or hash to hang up.",
+                count(new, "new message"),
+                count(messages.len() - new, "saved message"),
+            );
+            match self.ask(&text).await {
+                Some('1') => self.listen().await,
+                Some('2') => self.record_greeting().await,
+                _ => return,
+            }
+        }
+    }
+
+    async fn record_greeting(&mut self) {
+        self.say("Record your greeting after the tone, then press hash.").await;
+        let beep = self.keypad.media.codec.tone(BEEP_FREQ, BEEP_MS);
+        if let Err(e) = self.stream.play(&beep).await {
+            info!("Failed to play beep: {:?}", e);
+        }
+        self.keypad.discard().await;
+        let samples = self.keypad.record(MAX_GREETING).await;
+        if samples.len() < MIN_GREETING {
+            self.say("Nothing was recorded.").await;
+            return;
+        }
+        info!("greeting recorded: {} ms", samples.len() / 8);
+        let name = format!("{PHONE_GREETING}-{}", self.mailbox);
+        let name = if audio_file::valid_name(&name) { name } else { PHONE_GREETING.to_owned() };
+        let file = name.clone();
+        match tokio::task::spawn_blocking(move || audio_file::save(&file, &samples)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("{e}");
+                return;
+            }
+            Err(e) => {
+                error!("Failed to save greeting: {e}");
+                return;
+            }
+        }
+        let settings = self.settings(&self.mailbox).await.unwrap_or_else(|| Mailbox {
+            name: self.mailbox.clone(),
+            ..Default::default()
+        });
+        let active = Queries::SetMailbox(Mailbox { greeting: Some(name), ..settings });
+        if let Err(e) = execute(self.pool, active).await {
+            error!("Failed to activate greeting: {e}");
+        }
+        self.say("Your greeting has been saved.").await;
+    }
+}
+
+/// Run the phone menu until the caller is done or hangs up, the owner
+/// of the mailbox skips the login.
+#[allow(clippy::too_many_arguments)]
+pub async fn menu(
+    pool: &Pool,
+    conn: UdpConnection,
+    stream: RtpStream,
+    media: Negotiated,
+    latch: SharedLatch,
+    opt: MenuOption,
+    owner: bool,
+    token: CancellationToken,
+) {
+    let keypad = Keypad::new(conn, media, latch, stream.srtp(), opt.inband_dtmf);
+    let mailbox = opt.mailbox.clone();
+    let mut menu = Menu { pool, keypad, stream, opt, mailbox };
+    select! {
+        _ = token.cancelled() => info!("menu cancelled"),
+        _ = async {
+            if owner || menu.login().await {
+                menu.main_menu().await;
+            }
+            menu.say("Goodbye.").await;
+        } => info!("menu finished"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce() {
+        assert_eq!(spell("0312"), "0 3 1 2");
+        assert_eq!(spell("Alice"), "Alice");
+        assert_eq!(count(0, "new message"), "no new messages");
+        assert_eq!(count(1, "new message"), "1 new message");
+        assert_eq!(count(3, "saved message"), "3 saved messages");
+    }
+}
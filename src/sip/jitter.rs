@@ -0,0 +1,287 @@
+use crate::sip::codec::Codec;
+use std::{collections::BTreeMap, time::Instant};
+
+/// packets held for reordering, 60 ms at 20 ms ptime
+const DEPTH: usize = 3;
+/// longest timestamp gap filled in, larger jumps restart the timeline
+const MAX_GAP: u32 = 8000 * 2;
+/// lost frames replaced by the previous one before falling back to silence
+const PLC_FRAMES: usize = 3;
+
+/// Audio ready to be written at `offset` bytes into the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub offset: u64,
+    pub payload: Vec<u8>,
+    pub concealed: bool,
+}
+
+/// RFC 3550 receive statistics.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RtpStats {
+    base_seq: Option<u32>,
+    max_seq: u32,
+    pub received: u64,
+    pub duplicates: u64,
+    pub late: u64,
+    pub concealed: u64,
+    /// interarrival jitter in timestamp units
+    jitter: f64,
+    transit: Option<f64>,
+}
+
+impl RtpStats {
+    fn extend(&mut self, seq: u16) -> u32 {
+        if self.base_seq.is_none() {
+            self.base_seq = Some(seq as u32);
+            self.max_seq = seq as u32;
+            return seq as u32;
+        }
+        // pick the extended sequence number closest to the highest one seen
+        let cycles = self.max_seq & 0xffff_0000;
+        let candidates = [
+            cycles.wrapping_sub(0x1_0000) | seq as u32,
+            cycles | seq as u32,
+            cycles.wrapping_add(0x1_0000) | seq as u32,
+        ];
+        let ext = candidates
+            .into_iter()
+            .min_by_key(|c| (*c as i64 - self.max_seq as i64).abs())
+            .unwrap_or(seq as u32);
+        if ext > self.max_seq {
+            self.max_seq = ext;
+        }
+        ext
+    }
+
+    fn arrival(&mut self, ts: u32, arrival: f64) {
+        let transit = arrival * 8000.0 - ts as f64;
+        if let Some(prev) = self.transit {
+            let d = (transit - prev).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+
+    pub fn expected(&self) -> u64 {
+        self.base_seq
+            .map(|b| (self.max_seq - b) as u64 + 1)
+            .unwrap_or_default()
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.expected().saturating_sub(self.received)
+    }
+
+    /// extended highest sequence number received
+    pub fn highest(&self) -> u32 {
+        self.max_seq
+    }
+
+    pub fn jitter_units(&self) -> u32 {
+        self.jitter as u32
+    }
+
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter / 8.0
+    }
+}
+
+/// Reorders packets by sequence number and turns their timestamps into
+/// contiguous audio, concealing what was lost.
+#[derive(Debug)]
+pub struct JitterBuffer {
+    codec: Codec,
+    start: Instant,
+    pending: BTreeMap<u32, (u32, Vec<u8>)>,
+    /// extended sequence number of the last frame played out
+    played: Option<u32>,
+    /// timestamp expected for the next frame
+    next_ts: Option<u32>,
+    offset: u64,
+    last: Vec<u8>,
+    pub stats: RtpStats,
+}
+
+impl JitterBuffer {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            start: Instant::now(),
+            pending: BTreeMap::new(),
+            played: None,
+            next_ts: None,
+            offset: 0,
+            last: vec![],
+            stats: RtpStats::default(),
+        }
+    }
+
+    /// Count a packet that is not audio, such as a telephone-event.
+    pub fn skip(&mut self, seq: u16) {
+        self.stats.extend(seq);
+        self.stats.received += 1;
+    }
+
+    pub fn push(&mut self, seq: u16, ts: u32, payload: &[u8]) -> Vec<Frame> {
+        let ext = self.stats.extend(seq);
+        if self.played.is_some_and(|p| ext <= p) {
+            self.stats.late += 1;
+            return vec![];
+        }
+        if self.pending.contains_key(&ext) {
+            self.stats.duplicates += 1;
+            return vec![];
+        }
+        self.stats.received += 1;
+        self.stats.arrival(ts, self.start.elapsed().as_secs_f64());
+        self.pending.insert(ext, (ts, payload.to_vec()));
+
+        let mut out = vec![];
+        while self.pending.len() > DEPTH {
+            self.pop(&mut out);
+        }
+        out
+    }
+
+    /// Play out everything still held.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        let mut out = vec![];
+        while !self.pending.is_empty() {
+            self.pop(&mut out);
+        }
+        out
+    }
+
+    /// Start the message over, the next frame is written at offset 0.
+    /// What is held was said before and is dropped.
+    pub fn restart(&mut self) {
+        self.played = self.pending.last_key_value().map(|(ext, _)| *ext).or(self.played);
+        self.pending.clear();
+        self.next_ts = None;
+        self.last.clear();
+        self.offset = 0;
+    }
+
+    fn pop(&mut self, out: &mut Vec<Frame>) {
+        let Some((ext, (ts, payload))) = self.pending.pop_first() else {
+            return;
+        };
+        self.played = Some(ext);
+        let len = payload.len() as u32;
+        if let Some(next) = self.next_ts {
+            let gap = ts.wrapping_sub(next) as i32;
+            if gap < 0 {
+                // overlaps what was already played
+                if gap.unsigned_abs() >= len {
+                    self.stats.late += 1;
+                    return;
+                }
+                let skip = gap.unsigned_abs() as usize;
+                self.emit(out, payload[skip..].to_vec(), false);
+                self.next_ts = Some(ts.wrapping_add(len));
+                return;
+            }
+            if gap as u32 <= MAX_GAP {
+                self.conceal(out, gap as usize);
+            }
+        }
+        self.next_ts = Some(ts.wrapping_add(len));
+        self.last = payload.clone();
+        self.emit(out, payload, false);
+    }
+
+    fn conceal(&mut self, out: &mut Vec<Frame>, mut samples: usize) {
+        let frame = self.last.len().max(160);
+        let mut n = 0;
+        while samples > 0 {
+            let len = samples.min(frame);
+            let payload = if n < PLC_FRAMES && !self.last.is_empty() {
+                // repeat the last frame, fading out
+                let gain = 0.5f32.powi(n as i32 + 1);
+                (0..len)
+                    .map(|i| {
+                        let s = self.codec.decode(self.last[i % self.last.len()]);
+                        self.codec.encode((s as f32 * gain) as i16)
+                    })
+                    .collect()
+            } else {
+                vec![self.codec.silence(); len]
+            };
+            self.stats.concealed += 1;
+            self.emit(out, payload, true);
+            samples -= len;
+            n += 1;
+        }
+    }
+
+    fn emit(&mut self, out: &mut Vec<Frame>, payload: Vec<u8>, concealed: bool) {
+        let len = payload.len() as u64;
+        out.push(Frame {
+            offset: self.offset,
+            payload,
+            concealed,
+        });
+        self.offset += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jitter_buffer() {
+        let mut jb = JitterBuffer::new(Codec::Pcmu);
+        let frame = |i: u8| vec![i; 160];
+        let mut out = vec![];
+        // 3 and 4 swapped, 6 lost, 2 duplicated
+        for seq in [1u16, 2, 2, 4, 3, 5, 7, 8] {
+            let ts = seq as u32 * 160;
+            out.extend(jb.push(seq, ts, &frame(seq as u8)));
+        }
+        out.extend(jb.flush());
+
+        let offsets = out.iter().map(|f| f.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, (0..8).map(|i| i * 160).collect::<Vec<_>>());
+        let firsts = out.iter().map(|f| f.payload[0]).collect::<Vec<_>>();
+        assert_eq!(&firsts[..5], &[1, 2, 3, 4, 5]);
+        assert!(out[5].concealed);
+        assert_eq!(&firsts[6..], &[7, 8]);
+        assert_eq!(jb.stats.duplicates, 1);
+        assert_eq!(jb.stats.lost(), 1);
+    }
+
+    #[test]
+    fn test_sequence_wrap() {
+        let mut jb = JitterBuffer::new(Codec::Pcma);
+        let mut out = vec![];
+        for (i, seq) in [65534u16, 65535, 0, 1].into_iter().enumerate() {
+            out.extend(jb.push(seq, i as u32 * 160, &[0xd5; 160]));
+        }
+        out.extend(jb.flush());
+        assert_eq!(out.len(), 4);
+        assert_eq!(jb.stats.lost(), 0);
+        assert_eq!(out[3].offset, 480);
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut jb = JitterBuffer::new(Codec::Pcmu);
+        let mut out = vec![];
+        for seq in 1u16..=5 {
+            out.extend(jb.push(seq, seq as u32 * 160, &[seq as u8; 160]));
+        }
+        assert_eq!(out.len(), 2);
+        jb.restart();
+        // 3 to 5 were held, a late copy of 4 stays out of the new message
+        let mut out = jb.push(4, 4 * 160, &[4; 160]);
+        for seq in 8u16..=9 {
+            out.extend(jb.push(seq, seq as u32 * 160, &[seq as u8; 160]));
+        }
+        out.extend(jb.flush());
+        let frames = out.iter().map(|f| (f.offset, f.payload[0], f.concealed)).collect::<Vec<_>>();
+        assert_eq!(frames, [(0, 8, false), (160, 9, false)]);
+        assert_eq!(jb.stats.late, 1);
+    }
+}
@@ -0,0 +1,125 @@
+use clap::ValueEnum;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+/// Where media is sent when the caller's packets come from elsewhere
+/// than the SDP says, as they do behind NAT.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatchPolicy {
+    /// always send to the SDP address
+    Sdp,
+    /// send to where the caller's first valid packet came from
+    Latch,
+    /// latch only when the SDP address is private
+    #[default]
+    Private,
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local and link-local
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+/// Send destination of one media stream.
+#[derive(Debug, Clone, Copy)]
+pub struct MediaLatch {
+    policy: LatchPolicy,
+    sdp: SocketAddr,
+    latched: Option<SocketAddr>,
+}
+
+impl MediaLatch {
+    pub fn new(policy: LatchPolicy, sdp: SocketAddr) -> Self {
+        Self {
+            policy,
+            sdp,
+            latched: None,
+        }
+    }
+
+    pub fn shared(policy: LatchPolicy, sdp: SocketAddr) -> SharedLatch {
+        Arc::new(Mutex::new(Self::new(policy, sdp)))
+    }
+
+    fn enabled(&self) -> bool {
+        match self.policy {
+            LatchPolicy::Sdp => false,
+            LatchPolicy::Latch => true,
+            LatchPolicy::Private => is_private(self.sdp.ip()),
+        }
+    }
+
+    /// whether a packet source may still change the destination
+    pub fn pending(&self) -> bool {
+        self.enabled() && self.latched.is_none()
+    }
+
+    /// Offer the source of a packet that passed validation, returns true
+    /// when it became the destination.
+    pub fn observe(&mut self, from: SocketAddr) -> bool {
+        if !self.pending() {
+            return false;
+        }
+        self.latched = Some(from);
+        from != self.sdp
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.latched.unwrap_or(self.sdp)
+    }
+}
+
+pub type SharedLatch = Arc<Mutex<MediaLatch>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latch_policy() {
+        let private: SocketAddr = "192.168.1.20:4000".parse().unwrap();
+        let public: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let nat: SocketAddr = "198.51.100.7:61000".parse().unwrap();
+        let other: SocketAddr = "198.51.100.8:61000".parse().unwrap();
+
+        let mut sdp = MediaLatch::new(LatchPolicy::Sdp, private);
+        assert!(!sdp.observe(nat));
+        assert_eq!(sdp.target(), private);
+
+        let mut latch = MediaLatch::new(LatchPolicy::Latch, public);
+        assert!(latch.observe(nat));
+        // only the first source is taken
+        assert!(!latch.observe(other));
+        assert_eq!(latch.target(), nat);
+
+        let mut on_private = MediaLatch::new(LatchPolicy::Private, private);
+        assert!(on_private.observe(nat));
+        assert_eq!(on_private.target(), nat);
+        let mut on_public = MediaLatch::new(LatchPolicy::Private, public);
+        assert!(!on_public.observe(nat));
+        assert_eq!(on_public.target(), public);
+
+        assert!(is_private("100.72.1.1".parse().unwrap()));
+        assert!(is_private("fd00::1".parse().unwrap()));
+        assert!(!is_private("2001:db8::1".parse().unwrap()));
+    }
+}
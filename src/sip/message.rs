@@ -0,0 +1,28 @@
+//! Reading headers of SIP requests and responses that rsip keeps untyped.
+
+/// Value of header `name` however rsip parsed it.
+pub fn header(headers: &rsip::Headers, name: &str) -> Option<String> {
+    headers.iter().find_map(|h| {
+        let h = h.to_string();
+        let (n, v) = h.split_once(':')?;
+        n.trim().eq_ignore_ascii_case(name).then(|| v.trim().to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsip::{Header, headers};
+
+    #[test]
+    fn test_header() {
+        let headers: rsip::Headers = vec![
+            Header::CallId(headers::CallId::new("abc@192.0.2.1")),
+            Header::Other("Retry-After".to_owned(), "120 (maintenance)".to_owned()),
+        ]
+        .into();
+        assert_eq!(header(&headers, "call-id").as_deref(), Some("abc@192.0.2.1"));
+        assert_eq!(header(&headers, "Retry-After").as_deref(), Some("120 (maintenance)"));
+        assert_eq!(header(&headers, "Min-Expires"), None);
+    }
+}
@@ -1,35 +1,68 @@
-use crate::{lazy_regex, speech_to_text};
+use crate::{lazy_regex, speech_to_text, text_to_speech};
+use crate::sip::account::{Account, mailbox_of};
+use crate::sip::call::{CallContext, Calls};
+use crate::sip::codec::Codec;
+use crate::sip::dial::{DialAccount, DialContext, DialRequest, Dialer, process_dials};
+use crate::sip::greeting::CallerInfo;
+use crate::sip::latch::{LatchPolicy, MediaLatch};
+use crate::sip::register::{RegStatus, process_registrations};
+use crate::sip::resolve::Resolver;
+use crate::sip::sdp::{SdpError, SessionOffer, negotiate};
 use crate::sip::play_file::recved_call;
-use crate::utils::utc_time;
-use crate::web::db::Pool;
+use crate::sip::rtcp::SharedStats;
+use crate::sip::srtp::{Srtp, SrtpPolicy};
+use crate::text_to_speech::{Fields, TtsEngine};
+use crate::web::db::{DataType, Mailbox, Pool, Queries, active_greeting};
 use anyhow::{Error, Result};
 use clap::{Parser, ValueEnum};
-use play_file::{build_rtp_conn, play_audio_file, play_echo, write_pcm};
-use rsip::Header;
-use rsip::{prelude::HeadersExt, typed::MediaType};
+use ivr::MenuOption;
+use mwi::Subscriptions;
+use play_file::{
+    BargeIn, Greeting, RecordingOption, Recorded, RtpStream, build_rtp_conn, listen, play_echo,
+    play_greeting, play_prompt, rtcp_session, write_pcm,
+};
+use rsip::{Transport, prelude::HeadersExt, typed::MediaType};
 use rsipstack::{
     EndpointBuilder, Error as RsError,
     dialog::{
-        authenticate::Credential,
+        DialogId,
         dialog::{Dialog, DialogState, DialogStateReceiver, DialogStateSender},
         dialog_layer::DialogLayer,
-        registration::Registration,
         server_dialog::ServerInviteDialog,
     },
-    transaction::{TransactionReceiver, endpoint::EndpointInnerRef},
+    transaction::TransactionReceiver,
     transport::{TransportLayer, udp::UdpConnection},
 };
-use std::{env, fmt, net::IpAddr, sync::{Arc, LazyLock}, time::Duration};
+use std::{env, fmt, net::{IpAddr, SocketAddr}, sync::{Arc, LazyLock}, time::Instant};
 use tokio::{
     select,
-    sync::{Mutex, mpsc::unbounded_channel},
-    time::sleep,
+    sync::{Mutex, broadcast, mpsc::{UnboundedReceiver, unbounded_channel}},
 };
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info};
+use tracing::{error, info};
 use crate::sms::notify;
 
+mod account;
+pub mod audio_file;
+pub mod call;
+pub mod codec;
+pub mod dial;
+mod dtmf;
+pub mod greeting;
+mod ivr;
+mod jitter;
+pub mod latch;
+mod message;
+mod mwi;
 mod play_file;
+pub mod register;
+mod resolve;
+mod rtcp;
+mod rtp;
+pub mod sdp;
+mod srtp;
+mod transport;
+mod vad;
 
 lazy_regex!(
     RE: r"<[^:]+:(?<a>[^@]+)@.*$"
@@ -38,24 +71,29 @@ lazy_regex!(
 #[derive(Debug, Clone)]
 struct MediaSessionOption {
     pub cancel_token: CancellationToken,
-    pub from: Option<Header>,
     pub external_ip: Option<String>,
     pub rtp_start_port: u16,
     pub echo: bool,
     pub rec: bool,
     pub sms: bool,
+    pub codecs: Vec<Codec>,
+    pub latching: LatchPolicy,
+    pub srtp: SrtpPolicy,
+    pub recording: RecordingOption,
+    pub greeting: String,
+    pub play_greeting: bool,
+    pub tts: TtsEngine,
+    pub tts_voice: String,
+    pub pin: Option<String>,
+    pub owner: Option<String>,
+    pub extension: Option<String>,
+    /// calls to each account are recorded in its mailbox
+    pub accounts: Vec<Account>,
+    pub beep_freq: f32,
+    pub beep_ms: u32,
     pub ai_models: Option<AiModels>,
 }
 
-impl MediaSessionOption {
-    pub fn set_header(&mut self, header: Header) {
-        self.from = Some(header);
-    }
-    pub fn get_header(&self) -> Option<Header> {
-        self.from.clone()
-    }
-}
-
 /// A SIP client example that sends a REGISTER request to a SIP server.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -68,6 +106,22 @@ struct Args {
     #[arg(long, default_value = "5061")]
     rtp_start_port: u16,
 
+    /// Also listen for SIP over TCP on this port
+    #[arg(long)]
+    tcp_port: Option<u16>,
+
+    /// Also listen for SIP over TLS on this port, ex. 5061
+    #[arg(long)]
+    tls_port: Option<u16>,
+
+    /// PEM certificate chain for the TLS listener
+    #[arg(long)]
+    tls_cert: Option<String>,
+
+    /// PEM private key for the TLS listener
+    #[arg(long)]
+    tls_key: Option<String>,
+
     /// echo
     #[arg(long, default_value = "false")]
     echo: bool,
@@ -84,6 +138,11 @@ struct Args {
     #[arg(long)]
     sip_server: Option<String>,
 
+    /// Name server for the NAPTR/SRV lookups of the SIP server, ex.
+    /// 127.0.0.1:5353, the system resolver when unset
+    #[arg(long)]
+    dns_server: Option<SocketAddr>,
+
     /// SIP user
     #[arg(long)]
     user: Option<String>,
@@ -91,11 +150,101 @@ struct Args {
     /// SIP password
     #[arg(long)]
     password: Option<String>,
-    
+
+    /// Another SIP account, recording into the mailbox of its user:
+    /// user:password@host[:port][;transport=tcp], repeatable
+    #[arg(long)]
+    account: Vec<String>,
+
+    /// PIN for the menu opened by '*' during the greeting, digits only
+    #[arg(long)]
+    pin: Option<String>,
+
+    /// Caller numbers reaching the message menu without the PIN, comma separated
+    #[arg(long)]
+    owner: Option<String>,
+
+    /// Your own extension or SIP URI, rung first when calling a caller back
+    #[arg(long)]
+    extension: Option<String>,
+
     /// Send SNS
     #[arg(long, default_value = "false")]
     sms: bool,
 
+    /// Supported codecs in order of preference
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "pcmu,pcma")]
+    codecs: Vec<Codec>,
+
+    /// Where to send media when the caller is behind NAT
+    #[arg(long, value_enum, default_value = "private")]
+    media_latching: LatchPolicy,
+
+    /// Whether media is encrypted with SRTP keyed by SDES
+    #[arg(long, value_enum, default_value = "optional")]
+    srtp: SrtpPolicy,
+
+    /// Maximum message length in seconds
+    #[arg(long, default_value = "30")]
+    max_length: u64,
+
+    /// Messages shorter than this many seconds are deleted
+    #[arg(long, default_value = "0")]
+    min_length: u64,
+
+    /// Seconds before the maximum length to play a warning tone, 0 to disable
+    #[arg(long, default_value = "5")]
+    warning: u64,
+
+    /// Greeting played before recording: a name in ./assets or a path to a
+    /// WAV, AU, MP3, FLAC or raw G.711 file
+    #[arg(long, default_value = "voicemail")]
+    greeting: String,
+
+    /// Synthesizer for greetings given as "tts:text"
+    #[arg(long, value_enum, default_value = "espeak")]
+    tts: TtsEngine,
+
+    /// Voice of the synthesizer, ex. en or ja for eSpeak
+    #[arg(long, default_value = "en")]
+    tts_voice: String,
+
+    /// Start recording without playing the greeting
+    #[arg(long, default_value = "false")]
+    skip_greeting: bool,
+
+    /// Frequency of the record-start beep in Hz
+    #[arg(long, default_value = "1000")]
+    beep_freq: f32,
+
+    /// Length of the record-start beep in ms, 0 to disable
+    #[arg(long, default_value = "300")]
+    beep_ms: u32,
+
+    /// Detect DTMF sent as audio tones
+    #[arg(long, default_value = "false")]
+    inband_dtmf: bool,
+
+    /// Blank in-band DTMF tones in recordings
+    #[arg(long, default_value = "false")]
+    strip_dtmf: bool,
+
+    /// Seconds of silence that end a recording, 0 to disable
+    #[arg(long, default_value = "0")]
+    silence_timeout: u64,
+
+    /// Delete recordings without speech
+    #[arg(long, default_value = "false")]
+    discard_silent: bool,
+
+    /// Trim leading and trailing silence from recordings
+    #[arg(long, default_value = "false")]
+    trim_silence: bool,
+
+    /// What interrupts the greeting and starts recording at once
+    #[arg(long, value_enum, default_value = "dtmf")]
+    barge_in: BargeIn,
+
     #[arg(value_name = "Ai Models", default_value = "gcp")]
     ai_models: Option<AiModels>
 }
@@ -129,7 +278,40 @@ pub fn get_first_non_loopback_interface() -> Result<IpAddr> {
     )))
 }
 
-pub async fn voice_mail(pool: Pool) -> Result<()> {
+/// Transcode the greetings set on mailboxes and rules, reporting the
+/// broken ones.
+async fn check_mailbox_greetings(pool: &Pool, codecs: &[Codec]) {
+    for query in [Queries::AllMailbox, Queries::AllGreetingRules] {
+        let Ok(rows) = crate::web::db::execute(pool, query).await else {
+            continue;
+        };
+        for row in rows {
+            let (owner, greeting) = match row {
+                DataType::Mailbox(Mailbox { name, greeting: Some(greeting), .. }) => {
+                    (format!("mailbox {name}"), greeting)
+                }
+                DataType::GreetingRule(r) => (format!("greeting rule {:?}", r.id), r.greeting),
+                _ => continue,
+            };
+            if let Err(e) = greeting::validate_stored(&greeting, codecs) {
+                error!("{owner}: {e}");
+            }
+        }
+    }
+    if let Some(greeting) = active_greeting(pool).await
+        && let Err(e) = greeting::validate_stored(&greeting, codecs)
+    {
+        error!("active greeting: {e}");
+    }
+}
+
+pub async fn voice_mail(
+    pool: Pool,
+    calls: Calls,
+    dialer: Dialer,
+    dial_requests: UnboundedReceiver<DialRequest>,
+    registration: RegStatus,
+) -> Result<()> {
     if let Err(e) = dotenv::dotenv() {
         info!("Failed to load .env file: {}", e);
     }
@@ -138,6 +320,12 @@ pub async fn voice_mail(pool: Pool) -> Result<()> {
 
     info!("Starting SIP client");
 
+    // refuse to start with a greeting that cannot be played
+    if !args.skip_greeting || !args.rec {
+        greeting::validate(&args.greeting, &args.codecs).map_err(Error::msg)?;
+    }
+    check_mailbox_greetings(&pool, &args.codecs).await;
+
     let mut sip_server = args
         .sip_server
         .unwrap_or(env::var("SIP_SERVER").unwrap_or_default());
@@ -154,15 +342,73 @@ pub async fn voice_mail(pool: Pool) -> Result<()> {
         .password
         .unwrap_or(env::var("SIP_PASSWORD").unwrap_or_default());
 
+    let specs = match args.account.is_empty() {
+        true => env::var("SIP_ACCOUNTS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+        false => args.account,
+    };
+    let mut accounts = specs.iter().map(|s| Account::parse(s)).collect::<Result<Vec<_>>>()?;
+    if let Some(server) = sip_server {
+        accounts.insert(0, Account {
+            user: sip_username.clone(),
+            password: sip_password,
+            server,
+        });
+    }
+
+    // messages from before there were several accounts are the first one's
+    let default_mailbox = accounts.first().map_or(sip_username.as_str(), |a| a.mailbox()).to_owned();
+    crate::web::db::execute(&pool, Queries::AdoptMessages(default_mailbox))
+        .await
+        .map_err(|e| Error::msg(e.to_string()))?;
+
+    let pin = args
+        .pin
+        .or(env::var("VOICEMAIL_PIN").ok())
+        .filter(|p| !p.is_empty());
+    if pin.as_ref().is_some_and(|p| !p.chars().all(|c| c.is_ascii_digit())) {
+        return Err(Error::msg("the PIN must be digits"));
+    }
+
     let token = CancellationToken::new();
     let opt = Arc::new(Mutex::new(MediaSessionOption {
         cancel_token: token.clone(),
-        from: None,
         external_ip: args.external_ip.clone(),
         rtp_start_port: args.rtp_start_port,
         echo: args.echo,
         rec: args.rec,
         sms: args.sms,
+        codecs: args.codecs,
+        latching: args.media_latching,
+        srtp: args.srtp,
+        recording: RecordingOption {
+            max_length: args.max_length,
+            min_length: args.min_length,
+            warning: args.warning,
+            inband_dtmf: args.inband_dtmf,
+            strip_dtmf: args.strip_dtmf,
+            silence_timeout: args.silence_timeout,
+            discard_silent: args.discard_silent,
+            trim_silence: args.trim_silence,
+            barge_in: args.barge_in,
+            pin_menu: pin.is_some(),
+        },
+        greeting: args.greeting,
+        play_greeting: !args.skip_greeting,
+        tts: args.tts,
+        tts_voice: args.tts_voice,
+        pin,
+        owner: args.owner.or(env::var("VOICEMAIL_OWNER").ok()),
+        extension: args
+            .extension
+            .or(env::var("VOICEMAIL_EXTENSION").ok())
+            .filter(|e| !e.is_empty()),
+        accounts: accounts.clone(),
+        beep_freq: args.beep_freq,
+        beep_ms: args.beep_ms,
         ai_models: args.ai_models,
     }));
 
@@ -188,100 +434,127 @@ pub async fn voice_mail(pool: Pool) -> Result<()> {
 
     transport_layer.add_transport(connection.into());
 
+    let external_at = |port: u16| -> Result<Option<SocketAddr>> {
+        Ok(match external_ip.is_empty() {
+            true => None,
+            false => Some(format!("{}:{}", external_ip, port).parse()?),
+        })
+    };
+    if let Some(port) = args.tcp_port {
+        let local = SocketAddr::new(addr, port);
+        transport::listen_tcp(&transport_layer, local, external_at(port)?).await?;
+    }
+    if let Some(port) = args.tls_port {
+        let cert = args.tls_cert.or(env::var("SIP_TLS_CERT").ok());
+        let key = args.tls_key.or(env::var("SIP_TLS_KEY").ok());
+        let config = transport::tls_config(cert.as_deref(), key.as_deref())?;
+        let local = SocketAddr::new(addr, port);
+        transport::listen_tls(&transport_layer, local, external_at(port)?, config).await?;
+    }
+
     let endpoint = EndpointBuilder::new()
         .with_cancel_token(token.clone())
         .with_transport_layer(transport_layer)
         .build();
 
-    let credential = Credential {
-        username: sip_username.clone(),
-        password: sip_password,
-        realm: None,
-    };
-
     let incoming = endpoint.incoming_transactions()?;
     let dialog_layer = Arc::new(DialogLayer::new(endpoint.inner.clone()));
 
     let (state_sender, state_receiver) = unbounded_channel();
 
-    let first_addr = endpoint
-        .get_addrs()
-        .first()
-        .ok_or(crate::Error::Error("no address found".to_string()))?
-        .clone();
-
-    let contact = rsip::Uri {
-        scheme: Some(rsip::Scheme::Sip),
-        auth: Some(rsip::Auth {
-            user: sip_username,
-            password: None,
-        }),
-        host_with_port: first_addr.addr,
-        params: vec![],
-        headers: vec![],
+    let addrs = endpoint.get_addrs();
+    // our contact for `user` on the listener of the transport `server` is
+    // reached over, if any
+    let contact_for = |user: &str, server: Option<&rsip::Uri>| -> Result<rsip::Uri> {
+        let transport = server.map(transport::of_uri).unwrap_or(Transport::Udp);
+        let local_addr = addrs
+            .iter()
+            .find(|a| a.r#type == Some(transport))
+            .or(addrs.first())
+            .ok_or(crate::Error::Error("no address found".to_string()))?;
+        Ok(transport::contact(user.to_owned(), local_addr.addr.clone(), transport))
+    };
+    let contacts = match accounts.is_empty() {
+        true => vec![contact_for(&sip_username, None)?],
+        false => accounts
+            .iter()
+            .map(|a| contact_for(&a.user, Some(&a.server)))
+            .collect::<Result<Vec<_>>>()?,
+    };
+    for contact in &contacts {
+        info!("contact {contact}");
+    }
+
+    let dns_server = match args.dns_server {
+        Some(addr) => Some(addr),
+        None => env::var("SIP_DNS_SERVER").ok().map(|s| s.parse()).transpose()?,
+    };
+    let resolver = Arc::new(Resolver::new(dns_server)?);
+
+    let subscriptions = Subscriptions::new(endpoint.inner.clone(), pool.clone(), contacts.clone());
+    let dial_ctx = DialContext {
+        dialog_layer: dialog_layer.clone(),
+        accounts: accounts
+            .iter()
+            .zip(&contacts)
+            .map(|(account, contact)| DialAccount {
+                mailbox: account.mailbox().to_owned(),
+                from: rsip::Uri {
+                    auth: contact.auth.clone(),
+                    ..account.server.clone()
+                },
+                server: account.server.clone(),
+                contact: contact.clone(),
+                credential: account.credential(),
+            })
+            .collect(),
+        opt: opt.clone(),
     };
 
     select! {
         _ = endpoint.serve() => {
             info!("user agent finished");
         }
-        r = process_registration(endpoint.inner.clone(), sip_server, credential.clone(), token.clone()) => {
+        r = process_registrations(endpoint.inner.clone(), accounts.clone(), resolver, addrs.clone(), registration, token.clone()) => {
             info!("register loop finished {:?}", r);
         }
-        r = process_incoming_request(dialog_layer.clone(), incoming, state_sender.clone(), contact.clone(), opt.clone()) => {
+        r = process_incoming_request(dialog_layer.clone(), incoming, state_sender.clone(), contacts, accounts, subscriptions.clone()) => {
             info!("serve loop finished {:?}", r);
         }
-        r = process_dialog(dialog_layer.clone(), state_receiver, pool, opt.clone()) => {
+        r = process_dialog(dialog_layer.clone(), state_receiver, pool, calls, opt.clone()) => {
             info!("dialog loop finished {:?}", r);
         }
-    }
-    Ok(())
-}
-
-async fn process_registration(
-    endpoint: EndpointInnerRef,
-    sip_server: Option<rsip::Uri>,
-    credential: Credential,
-    cancel_token: CancellationToken,
-) -> Result<()> {
-    let sip_server = match sip_server {
-        Some(uri) => uri,
-        None => {
-            cancel_token.cancelled().await;
-            return Ok(());
+        r = process_dials(dial_ctx, dialer, dial_requests) => {
+            info!("dial loop finished {:?}", r);
         }
-    };
-
-    let mut registration = Registration::new(endpoint, Some(credential));
-    loop {
-        let resp = registration.register(sip_server.clone(), None).await?;
-        debug!("received response: {}", resp.to_string());
-        if resp.status_code != rsip::StatusCode::OK {
-            return Err(Error::from(RsError::Error(
-                "Failed to register".to_string(),
-            )));
+        r = subscriptions.clone().process_changes() => {
+            info!("message waiting loop finished {:?}", r);
         }
-        sleep(Duration::from_secs(registration.expires().max(50) as u64)).await;
     }
+    Ok(())
 }
 
 async fn process_incoming_request(
     dialog_layer: Arc<DialogLayer>,
     mut incoming: TransactionReceiver,
     state_sender: DialogStateSender,
-    contact: rsip::Uri,
-    opt: Arc<Mutex<MediaSessionOption>>,
+    contacts: Vec<rsip::Uri>,
+    accounts: Vec<Account>,
+    subscriptions: Subscriptions,
 ) -> Result<()> {
     while let Some(mut tx) = incoming.recv().await {
         info!("Received transaction: {:?}", tx.key);
 
-        let recv_from = tx
-            .original
-            .headers
-            .iter()
-            .filter(|x| x.to_string().starts_with("From"))
-            .collect::<Vec<_>>();
-        opt.lock().await.set_header(recv_from[0].clone());
+        // subscriptions are not dialogs of the dialog layer, refreshes included
+        if tx.original.method == rsip::Method::Subscribe {
+            let subscriptions = subscriptions.clone();
+            tokio::spawn(async move {
+                if let Err(e) = subscriptions.subscribe(tx).await {
+                    info!("Failed to handle SUBSCRIBE: {e}");
+                }
+            });
+            continue;
+        }
 
         if tx.original.to_header()?.tag()?.as_ref().is_some() {
             match dialog_layer.match_dialog(&tx.original) {
@@ -307,7 +580,7 @@ async fn process_incoming_request(
                     &tx,
                     state_sender.clone(),
                     None,
-                    Some(contact.clone()),
+                    Some(contact_of(&contacts, &accounts, &tx.original).clone()),
                 ) {
                     Ok(d) => d,
                     Err(e) => {
@@ -336,6 +609,7 @@ async fn process_dialog(
     dialog_layer: Arc<DialogLayer>,
     state_receiver: DialogStateReceiver,
     pool: Pool,
+    calls: Calls,
     opt: Arc<Mutex<MediaSessionOption>>,
 ) -> Result<()> {
     let mut state_receiver = state_receiver;
@@ -352,8 +626,15 @@ async fn process_dialog(
                 };
                 match dialog {
                     Dialog::ServerInvite(d) => {
+                        let caller = caller_id(&d.initial_request());
+                        let mut ctx = CallContext::new(caller);
+                        ctx.mailbox = mailbox_name(&d.initial_request(), &opt.lock().await.accounts);
+                        calls.insert(id.clone(), ctx).await;
                         // play example pcmu of handling incoming call
-                        process_invite(opt.clone(), pool.clone(), d).await?;
+                        // a call that cannot be answered must not end the service
+                        if let Err(e) = process_invite(opt.clone(), pool.clone(), calls.clone(), id.clone(), d).await {
+                            info!("Failed to answer dialog {}: {:?}", id, e);
+                        }
                     }
                     Dialog::ClientInvite(_) => {
                         info!("Client invite dialog {}", id);
@@ -365,6 +646,7 @@ async fn process_dialog(
             }
             DialogState::Terminated(id, reason) => {
                 info!("Dialog terminated {} {:?}", id, reason);
+                calls.remove(&id).await;
                 dialog_layer.remove_dialog(&id);
             }
             _ => {
@@ -375,59 +657,160 @@ async fn process_dialog(
     Ok(())
 }
 
+fn caller_id(req: &rsip::Request) -> String {
+    let from = req
+        .from_header()
+        .map(|h| h.to_string())
+        .unwrap_or_default();
+    let caller = RE
+        .captures_iter(&from)
+        .map(|cap| cap["a"].to_owned())
+        .collect::<String>();
+    match caller.is_empty() {
+        true => "unknown caller".to_owned(),
+        false => caller,
+    }
+}
+
+/// Mailbox a call is for, the account named by the Request-URI or To
+/// user, else the one it reached.
+fn mailbox_name(req: &rsip::Request, accounts: &[Account]) -> String {
+    let ruri = req.uri.auth.as_ref().map(|a| a.user.clone()).unwrap_or_default();
+    let to = req.to_header().ok().and_then(|h| h.uri().ok());
+    let user = to.as_ref().and_then(|uri| uri.auth.as_ref()).map(|a| a.user.as_str());
+    let domain = to.as_ref().map(|uri| uri.host_with_port.host.to_string());
+    mailbox_of(accounts, &ruri, user, domain.as_deref())
+}
+
+/// Our contact for the account `req` is addressed to, the first when
+/// none is.
+fn contact_of<'a>(contacts: &'a [rsip::Uri], accounts: &[Account], req: &rsip::Request) -> &'a rsip::Uri {
+    let user = mailbox_name(req, accounts);
+    contacts
+        .iter()
+        .find(|c| c.auth.as_ref().is_some_and(|a| a.user == user))
+        .unwrap_or(&contacts[0])
+}
+
+async fn mailbox_settings(pool: &Pool, name: &str) -> Mailbox {
+    match crate::web::db::execute(pool, Queries::Mailbox(name.to_owned())).await {
+        Ok(rows) => match rows.into_iter().next() {
+            Some(DataType::Mailbox(m)) => m,
+            _ => Mailbox::default(),
+        },
+        Err(e) => {
+            info!("Failed to read mailbox {name}: {e}");
+            Mailbox::default()
+        }
+    }
+}
+
+/// Name of the caller in the contacts.
+async fn contact_name(pool: &Pool, caller: &str) -> Option<String> {
+    match crate::web::db::execute(pool, Queries::Contact(caller.to_owned())).await {
+        Ok(rows) => rows.into_iter().find_map(|r| match r {
+            DataType::Contact { name, .. } => Some(name),
+            _ => None,
+        }),
+        Err(e) => {
+            info!("Failed to read contact {caller}: {e}");
+            None
+        }
+    }
+}
+
+/// Greeting chosen by the rules for this caller, if any applies.
+async fn rule_greeting(pool: &Pool, mailbox: &str, caller: &str, known: bool) -> Option<String> {
+    let rules = crate::web::db::execute(pool, Queries::AllGreetingRules)
+        .await
+        .inspect_err(|e| info!("Failed to read greeting rules: {e}"))
+        .ok()?
+        .into_iter()
+        .filter_map(|r| match r {
+            DataType::GreetingRule(r) => Some(r),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if rules.is_empty() {
+        return None;
+    }
+    let call = CallerInfo { mailbox, caller, known };
+    let rule = greeting::select(&rules, &call, chrono::Local::now().naive_local())?;
+    info!("greeting rule {:?} for {caller}: {}", rule.id, rule.greeting);
+    Some(rule.greeting.clone())
+}
+
+/// Render a text greeting, files are loaded when played.
+async fn greeting_audio(
+    pool: &Pool,
+    greeting: String,
+    engine: TtsEngine,
+    voice: &str,
+    fields: &Fields<'_>,
+) -> Option<Greeting> {
+    let Some(template) = text_to_speech::text(&greeting) else {
+        return Some(Greeting::File(greeting));
+    };
+    let text = text_to_speech::expand(template, fields)
+        .inspect_err(|e| error!("greeting '{greeting}': {e}"))
+        .ok()?;
+    match text_to_speech::render(pool, engine, voice, &text, !text_to_speech::has_fields(template)).await {
+        Ok(pcmu) => Some(Greeting::Speech(pcmu)),
+        Err(e) => {
+            error!("Failed to render greeting '{text}': {e}");
+            None
+        }
+    }
+}
+
 async fn process_invite(
     opt: Arc<Mutex<MediaSessionOption>>,
     pool: Pool,
+    calls: Calls,
+    id: DialogId,
     dialog: ServerInviteDialog,
 ) -> Result<()> {
     let ssrc = rand::random::<u32>();
 
-    let caller = RE
-        .captures_iter(&opt.lock().await.get_header().unwrap().to_string())
-        .map(|cap| cap["a"].to_owned())
-        .map(|x| {
-            match x.is_empty() {
-                true => "unknown caller".to_owned(),
-                false => x,
-            }
-        })
-        .collect::<String>();
+    let caller = calls
+        .get(&id)
+        .await
+        .map(|c| c.caller)
+        .unwrap_or_else(|| caller_id(&dialog.initial_request()));
 
     let body = String::from_utf8_lossy(dialog.initial_request().body()).to_string();
-    let offer = match sdp_rs::SessionDescription::try_from(body.as_str()) {
-        Ok(s) => s,
-        Err(e) => {
-            info!("Failed to parse offer SDP: {:?} {}", e, body);
-            return Err(Error::from(RsError::Error(
-                "Failed to parse SDP".to_string(),
-            )));
-        }
+    let (codecs, policy) = {
+        let lock = opt.lock().await;
+        (lock.codecs.clone(), lock.srtp)
     };
-
-    let peer_addr = match offer.connection {
-        Some(c) => c.connection_address.base,
-        None => {
-            info!("No connection address in offer SDP");
-            return Err(Error::from(RsError::Error(
-                "No connection address in offer SDP".to_string(),
-            )));
+    let (offer, media) = match SessionOffer::parse(&body)
+        .and_then(|offer| negotiate(&offer, &codecs, policy).map(|m| (offer, m)))
+    {
+        Ok(r) => r,
+        Err(e) => {
+            info!("Rejecting offer SDP: {} {}", e, body);
+            let code = match e {
+                SdpError::NotAcceptable => rsip::StatusCode::NotAcceptableHere,
+                SdpError::Parse(_) => rsip::StatusCode::BadRequest,
+            };
+            dialog.reject(Some(code), None)?;
+            return Ok(());
         }
     };
-
-    let peer_port = offer
-        .media_descriptions
-        .first()
-        .map(|m| m.media.port)
-        .ok_or(Error::from(RsError::Error(
-            "No audio port in offer SDP".to_string(),
-        )))?;
-    let payload_type = offer
-        .media_descriptions
-        .first()
-        .and_then(|m| m.media.fmt.parse::<u8>().ok())
-        .unwrap_or(0);
-
-    let (conn, answer) = build_rtp_conn(opt.clone(), ssrc, payload_type).await?;
+    let codec = media.codec;
+    let payload_type = codec.payload_type();
+    let peer_addr = media.peer.ip();
+    let peer_port = media.peer.port();
+
+    let (conn, rtcp, answer) = build_rtp_conn(opt.clone(), ssrc, &offer, &media).await?;
+    let rtp_addr: Option<SocketAddr> = conn.get_addr().addr.to_owned().try_into().ok();
+    calls
+        .update(&id, |c| {
+            c.payload_type = Some(payload_type);
+            c.rtp_addr = rtp_addr;
+            c.peer_addr = Some(media.peer);
+        })
+        .await;
 
     let headers = vec![rsip::typed::ContentType(MediaType::Sdp(vec![])).into()];
     dialog.accept(Some(headers), Some(answer.clone().into()))?;
@@ -437,54 +820,153 @@ async fn process_invite(
         peer_addr, peer_port, payload_type
     );
 
-    let peer_addr = format!("{}:{}", peer_addr, peer_port);
+    // a copy, so a slow greeting render holds up no other call
+    let options = opt.lock().await.clone();
     let rtp_token = dialog.cancel_token().child_token();
-    let lock = opt.lock().await;
-    let rec = lock.rec;
-    let echo = lock.echo;
-    let sms = lock.sms;
-    let ai_models = lock.ai_models.clone().unwrap();
+    let stats = SharedStats::default();
+    let latching = options.latching;
+    let latch = MediaLatch::shared(latching, media.peer);
+    let srtp = Srtp::new(media.srtp);
+    let rtcp_token = rtp_token.child_token();
+    tokio::spawn(rtcp_session(rtcp, media, ssrc, stats.clone(), latching, srtp.clone(), rtcp_token.clone()));
+    let events = calls
+        .get(&id)
+        .await
+        .map(|c| c.events)
+        .unwrap_or_else(|| broadcast::channel(16).0);
+    let rec = options.rec;
+    let echo = options.echo;
+    let mailbox_id = mailbox_name(&dialog.initial_request(), &options.accounts);
 
     tokio::spawn(async move {
+        let mailbox = mailbox_settings(&pool, &mailbox_id).await;
+        let sms = mailbox.notify.unwrap_or(options.sms);
+        let sns_topic = mailbox.sns_topic.clone();
+        let mut rec_opt = options.recording;
+        rec_opt.max_length = mailbox.max_length.unwrap_or(rec_opt.max_length);
+        rec_opt.min_length = mailbox.min_length.unwrap_or(rec_opt.min_length);
+        rec_opt.warning = mailbox.warning.unwrap_or(rec_opt.warning);
+        rec_opt.pin_menu |= mailbox.pin.is_some();
+        let owner = mailbox
+            .owner
+            .as_deref()
+            .or(options.owner.as_deref())
+            .is_some_and(|o| o.split(',').any(|n| n.trim() == caller));
+        let contact = contact_name(&pool, &caller).await;
+        let greeting = match rule_greeting(&pool, &mailbox_id, &caller, contact.is_some()).await {
+            Some(greeting) => greeting,
+            None => match mailbox.greeting {
+                Some(greeting) => greeting,
+                None => active_greeting(&pool).await.unwrap_or_else(|| options.greeting.clone()),
+            },
+        };
+        let play_greeting = mailbox.play_greeting.unwrap_or(options.play_greeting);
+        let fields = Fields {
+            name: contact.as_deref().unwrap_or(&caller),
+            caller: &caller,
+            mailbox: &mailbox_id,
+            now: chrono::Local::now().naive_local(),
+        };
+        let greeting = match echo || owner || (rec && !play_greeting) {
+            true => None,
+            false => greeting_audio(&pool, greeting, options.tts, &options.tts_voice, &fields).await,
+        };
+        let (beep_freq, beep_ms) = (options.beep_freq, options.beep_ms);
+        let ai_models = mailbox
+            .ai_models
+            .as_deref()
+            .and_then(|m| AiModels::from_str(m, true).ok())
+            .or(options.ai_models.clone())
+            .unwrap();
+        let menu_opt = MenuOption {
+            mailbox: mailbox_id.clone(),
+            pin: options.pin.clone(),
+            inband_dtmf: rec_opt.inband_dtmf,
+            tts: options.tts,
+            voice: options.tts_voice.clone(),
+        };
+
         select! {
             _ = async {
                 let headers = vec![rsip::typed::ContentType(MediaType::Sdp(vec![])).into()];
                 match dialog.accept(Some(headers), Some(answer.clone().into())) {
-                    Ok(_) => info!("Accepted call with answer SDP peer address: {} port: {} payload_type: {}", peer_addr, peer_port, payload_type),
+                    Ok(_) => {
+                        info!("Accepted call with answer SDP peer address: {} port: {} payload_type: {}", peer_addr, peer_port, payload_type);
+                        calls.update(&id, |c| c.answered = Some(Instant::now())).await;
+                    }
                     Err(e) => {
                         error!("Failed to accept call: {:?}", e);
                         return;
                     }
                 }
                 if echo {
-                    play_echo(conn, rtp_token).await.expect("play echo");
-                } else if rec {
-                    let id = utc_time().parse::<i64>().unwrap();
-                    recved_call(&pool, id, caller.clone()).await.expect("");
-                    play_audio_file(conn.clone(), ssrc, "voicemail", peer_addr, payload_type)
+                    play_echo(conn, rtp_token, media, latch, srtp).await.expect("play echo");
+                } else if owner && media.direction.sends() && media.direction.receives() {
+                    info!("owner {caller} calling, opening the menu");
+                    let stream = RtpStream::new(conn.clone(), ssrc, media, stats, latch.clone(), srtp);
+                    ivr::menu(&pool, conn, stream, media, latch, menu_opt, true, rtp_token).await;
+                } else if rec && media.direction.receives() {
+                    let record_id = match recved_call(&pool, mailbox_id.clone(), caller.clone(), codec, rec_opt.max_length).await {
+                        Ok(record_id) => record_id,
+                        Err(e) => {
+                            error!("Failed to store the call: {e}");
+                            return;
+                        }
+                    };
+                    calls.update(&id, |c| c.record_id = Some(record_id)).await;
+                    let id = record_id;
+                    // the prompt plays while write_pcm listens for a barge-in,
+                    // only what the caller says after it is recorded
+                    let prompt = CancellationToken::new();
+                    let stream = media.direction.sends().then(|| {
+                        let stream = RtpStream::new(conn.clone(), ssrc, media, stats.clone(), latch.clone(), srtp.clone());
+                        let beep = (beep_ms > 0).then_some((beep_freq, beep_ms));
+                        play_prompt(stream, greeting, beep, prompt.clone())
+                    });
+                    if stream.is_none() {
+                        prompt.cancel();
+                    }
+                    let recorded = write_pcm(conn.clone(), &pool, rtp_token.clone(), id, media, events, rec_opt, stream, stats, latch.clone(), prompt, srtp)
                         .await
-                        .expect("play example file");
-                    write_pcm(conn, &pool, rtp_token, id).await.expect("rec voice");
-                    info!("write pcm finished");
-                    if sms {
-                        tokio::spawn(async move {
-                            let txt = speech_to_text::execute(&pool, id, ai_models)
-                                .await.unwrap_or_else(|e| format!("error: {}", e));
-                            info!("{txt}");
-                            notify(&format!("received call from {}\n{}",
-                                        &caller, txt)).await.expect("notify");
+                        .unwrap_or_else(|e| {
+                            info!("Failed to record message {id}: {:?}", e);
+                            Recorded::Discarded
                         });
-                        info!("send sms");
+                    info!("write pcm finished");
+                    match recorded {
+                        Recorded::Kept if sms => {
+                            tokio::spawn(async move {
+                                let txt = speech_to_text::execute(&pool, id, ai_models)
+                                    .await.unwrap_or_else(|e| format!("error: {}", e));
+                                info!("{txt}");
+                                notify(&format!("received call from {}\n{}",
+                                            &caller, txt), sns_topic.as_deref()).await.expect("notify");
+                            });
+                            info!("send sms");
+                        }
+                        Recorded::Menu(Some(stream)) => {
+                            if let Ok(stream) = stream.await {
+                                ivr::menu(&pool, conn, stream, media, latch, menu_opt, false, rtp_token).await;
+                            }
+                        }
+                        _ => {}
                     }
-                } else {
-                    play_audio_file(conn, ssrc, "voicemail", peer_addr, payload_type)
-                        .await
-                        .expect("play example file");
+                } else if media.direction.sends() {
+                    let listen_token = rtp_token.child_token();
+                    tokio::spawn(listen(conn.clone(), media, latch.clone(), srtp.clone(), listen_token.clone()));
+                    let mut stream = RtpStream::new(conn, ssrc, media, stats, latch, srtp);
+                    if let Some(greeting) = &greeting {
+                        play_greeting(&mut stream, greeting)
+                            .await
+                            .expect("play example file");
+                    }
+                    listen_token.cancel();
                 }
             } => {
                 info!("answer receiver finished");
             }
         }
+        rtcp_token.cancel();
         dialog.bye().await.expect("send BYE");
     });
     Ok(())
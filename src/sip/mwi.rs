@@ -0,0 +1,370 @@
+//! Message waiting indication: the message-summary event package
+//! (RFC 3842) over SUBSCRIBE/NOTIFY, so desk phones light their lamp.
+
+use crate::sip::message::header;
+use crate::sip::transport;
+use crate::web::db::{DataType, Pool, Queries, execute, message_changes};
+use anyhow::Result;
+use rsip::{Header, headers};
+use rsipstack::transaction::{
+    endpoint::EndpointInnerRef,
+    key::{TransactionKey, TransactionRole},
+    transaction::Transaction,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{select, sync::Mutex, time::interval};
+use tracing::{error, info};
+
+pub const EVENT: &str = "message-summary";
+const CONTENT_TYPE: &str = "application/simple-message-summary";
+const MAX_EXPIRES: u32 = 3600;
+const MIN_EXPIRES: u32 = 60;
+/// how often expired subscriptions are looked for
+const SWEEP: Duration = Duration::from_secs(30);
+
+/// New and old message counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub new: u64,
+    pub old: u64,
+}
+
+impl Summary {
+    /// application/simple-message-summary body for `account`.
+    pub fn body(&self, account: &str) -> String {
+        let waiting = if self.new > 0 { "yes" } else { "no" };
+        format!(
+            "Messages-Waiting: {waiting}\r\n\
+            Message-Account: {account}\r\n\
+            Voice-Message: {}/{} (0/0)\r\n",
+            self.new, self.old
+        )
+    }
+}
+
+/// Seconds granted for a requested Expires, None when too brief.
+fn granted(requested: Option<u32>) -> Option<u32> {
+    match requested {
+        None => Some(MAX_EXPIRES),
+        Some(0) => Some(0),
+        Some(e) if e < MIN_EXPIRES => None,
+        Some(e) => Some(e.min(MAX_EXPIRES)),
+    }
+}
+
+/// The URI of a From, To or Contact value.
+fn addr_spec(value: &str) -> &str {
+    match value.split_once('<') {
+        Some((_, rest)) => rest.split('>').next().unwrap_or(rest),
+        None => value.split(';').next().unwrap_or(value).trim(),
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    /// To of the SUBSCRIBE with our tag, the From of our NOTIFYs
+    local: String,
+    /// From of the SUBSCRIBE
+    remote: String,
+    target: rsip::Uri,
+    account: String,
+    /// whose messages are counted, the user of the account
+    mailbox: String,
+    /// ours for the account
+    contact: rsip::Uri,
+    cseq: u32,
+    expires: Instant,
+}
+
+/// Active message-summary subscriptions keyed by Call-ID.
+#[derive(Clone)]
+pub struct Subscriptions {
+    endpoint: EndpointInnerRef,
+    pool: Pool,
+    /// ours for each account, the first for subscriptions to none
+    contacts: Vec<rsip::Uri>,
+    subs: Arc<Mutex<HashMap<String, Subscription>>>,
+}
+
+impl Subscriptions {
+    pub fn new(endpoint: EndpointInnerRef, pool: Pool, contacts: Vec<rsip::Uri>) -> Self {
+        Self {
+            endpoint,
+            pool,
+            contacts,
+            subs: Default::default(),
+        }
+    }
+
+    fn contact(&self, user: &str) -> &rsip::Uri {
+        self.contacts
+            .iter()
+            .find(|c| c.auth.as_ref().is_some_and(|a| a.user == user))
+            .unwrap_or(&self.contacts[0])
+    }
+
+    /// Whether `mailbox` is the user of one of our accounts or has
+    /// settings of its own.
+    async fn known(&self, mailbox: &str) -> bool {
+        if self.contacts.iter().any(|c| c.auth.as_ref().is_some_and(|a| a.user == mailbox)) {
+            return true;
+        }
+        match execute(&self.pool, Queries::Mailbox(mailbox.to_owned())).await {
+            Ok(rows) => !rows.is_empty(),
+            Err(e) => {
+                error!("Failed to read mailbox {mailbox}: {e}");
+                false
+            }
+        }
+    }
+
+    async fn summary(&self, mailbox: &str) -> Summary {
+        match execute(&self.pool, Queries::MessageCounts(mailbox.to_owned())).await {
+            Ok(rows) => match rows.first() {
+                Some(DataType::Counts { new, old }) => Summary { new: *new, old: *old },
+                _ => Summary::default(),
+            },
+            Err(e) => {
+                error!("Failed to count messages: {e}");
+                Summary::default()
+            }
+        }
+    }
+
+    /// Answer a SUBSCRIBE, new or a refresh, and send the current summary.
+    pub async fn subscribe(&self, mut tx: Transaction) -> Result<()> {
+        let req = tx.original.clone();
+        let event = header(&req.headers, "Event").unwrap_or_default();
+        if !event.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(EVENT) {
+            info!("unsupported event package: {event}");
+            tx.respond(response(&req, rsip::StatusCode::BadEvent, None, vec![])).await?;
+            return Ok(());
+        }
+        let mailbox = req.uri.auth.as_ref().map(|a| a.user.clone()).unwrap_or_default();
+        if !self.known(&mailbox).await {
+            info!("{EVENT} subscription to unknown mailbox '{mailbox}'");
+            tx.reply(rsip::StatusCode::NotFound).await?;
+            return Ok(());
+        }
+        let call_id = header(&req.headers, "Call-ID").unwrap_or_default();
+        let to = header(&req.headers, "To").unwrap_or_default();
+        let refresh = to.contains(";tag=");
+        if refresh && !self.subs.lock().await.contains_key(&call_id) {
+            tx.reply(rsip::StatusCode::CallTransactionDoesNotExist).await?;
+            return Ok(());
+        }
+        let requested = header(&req.headers, "Expires").and_then(|e| e.parse().ok());
+        let Some(expires) = granted(requested) else {
+            let min = Header::Other("Min-Expires".to_owned(), MIN_EXPIRES.to_string());
+            tx.respond(response(&req, rsip::StatusCode::IntervalTooBrief, None, vec![min]))
+                .await?;
+            return Ok(());
+        };
+
+        let tag = format!("{:08x}", rand::random::<u32>());
+        let local = if refresh { to } else { format!("{to};tag={tag}") };
+        let remote = header(&req.headers, "From").unwrap_or_default();
+        let target = header(&req.headers, "Contact").unwrap_or(remote.clone());
+        let target = rsip::Uri::try_from(addr_spec(&target))?;
+        let contact = self.contact(&mailbox).clone();
+        let extra = vec![
+            Header::Other("Expires".to_owned(), expires.to_string()),
+            Header::Contact(headers::Contact::new(format!("<{contact}>"))),
+        ];
+        tx.respond(response(&req, rsip::StatusCode::OK, (!refresh).then_some(&local), extra))
+            .await?;
+
+        info!("{EVENT} subscription {call_id} from {target} for {expires} s");
+        let until = Instant::now() + Duration::from_secs(expires.into());
+        {
+            let mut subs = self.subs.lock().await;
+            let sub = subs.entry(call_id.clone()).or_insert_with(|| Subscription {
+                local,
+                remote,
+                target: target.clone(),
+                account: req.uri.to_string(),
+                mailbox: mailbox.clone(),
+                contact,
+                cseq: 0,
+                expires: until,
+            });
+            sub.target = target;
+            sub.expires = until;
+        }
+        let summary = self.summary(&mailbox).await;
+        self.notify(summary, |id, _| id == call_id).await;
+        Ok(())
+    }
+
+    /// NOTIFY `summary` to the subscriptions `pick` selects, ending
+    /// the expired ones.
+    async fn notify(&self, summary: Summary, pick: impl Fn(&str, &Subscription) -> bool) {
+        let now = Instant::now();
+        let requests = {
+            let mut subs = self.subs.lock().await;
+            let requests = subs
+                .iter_mut()
+                .filter(|(id, sub)| pick(id, sub))
+                .map(|(id, sub)| {
+                    sub.cseq += 1;
+                    (id.clone(), self.request(id, sub, summary, now))
+                })
+                .collect::<Vec<_>>();
+            subs.retain(|_, sub| sub.expires > now);
+            requests
+        };
+        for (id, req) in requests {
+            if let Err(e) = self.send(req).await {
+                info!("NOTIFY of {id} failed, ending the subscription: {e}");
+                self.subs.lock().await.remove(&id);
+            }
+        }
+    }
+
+    fn request(&self, call_id: &str, sub: &Subscription, summary: Summary, now: Instant) -> rsip::Request {
+        let state = if sub.expires > now {
+            format!("active;expires={}", (sub.expires - now).as_secs())
+        } else {
+            "terminated;reason=timeout".to_owned()
+        };
+        let body = summary.body(&sub.account).into_bytes();
+        let via = format!(
+            "SIP/2.0/{} {};branch=z9hG4bK{:016x}",
+            transport::of_uri(&sub.contact),
+            sub.contact.host_with_port,
+            rand::random::<u64>()
+        );
+        let headers: Vec<Header> = vec![
+            Header::Via(headers::Via::new(via)),
+            Header::MaxForwards(headers::MaxForwards::new("70")),
+            Header::From(headers::From::new(sub.local.clone())),
+            Header::To(headers::To::new(sub.remote.clone())),
+            Header::CallId(headers::CallId::new(call_id)),
+            Header::CSeq(headers::CSeq::new(format!("{} NOTIFY", sub.cseq))),
+            Header::Contact(headers::Contact::new(format!("<{}>", sub.contact))),
+            Header::Other("Event".to_owned(), EVENT.to_owned()),
+            Header::Other("Subscription-State".to_owned(), state),
+            Header::ContentType(headers::ContentType::new(CONTENT_TYPE)),
+            Header::ContentLength(headers::ContentLength::new(body.len().to_string())),
+        ];
+        rsip::Request {
+            method: rsip::Method::Notify,
+            uri: sub.target.clone(),
+            version: rsip::Version::V2,
+            headers: headers.into(),
+            body,
+        }
+    }
+
+    async fn send(&self, req: rsip::Request) -> Result<()> {
+        let key = TransactionKey::from_request(&req, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, req, self.endpoint.clone(), None);
+        tx.send().await?;
+        while let Some(msg) = tx.receive().await {
+            if let rsip::SipMessage::Response(resp) = msg
+                && resp.status_code.code() >= 200
+            {
+                if resp.status_code.code() >= 300 {
+                    anyhow::bail!("{}", resp.status_code);
+                }
+                return Ok(());
+            }
+        }
+        anyhow::bail!("no response")
+    }
+
+    /// Mailboxes with subscriptions.
+    async fn mailboxes(&self) -> HashSet<String> {
+        self.subs.lock().await.values().map(|sub| sub.mailbox.clone()).collect()
+    }
+
+    /// NOTIFY the subscribers of each mailbox whose counts change and
+    /// end expired subscriptions.
+    pub async fn process_changes(self) -> Result<()> {
+        let mut changes = message_changes();
+        let mut sweep = interval(SWEEP);
+        let mut last = HashMap::new();
+        loop {
+            select! {
+                r = changes.changed() => {
+                    r?;
+                    for mailbox in self.mailboxes().await {
+                        let summary = self.summary(&mailbox).await;
+                        if last.insert(mailbox.clone(), summary) != Some(summary) {
+                            info!("messages waiting in {mailbox}: {}/{}", summary.new, summary.old);
+                            self.notify(summary, |_, sub| sub.mailbox == mailbox).await;
+                        }
+                    }
+                }
+                _ = sweep.tick() => {
+                    let now = Instant::now();
+                    for mailbox in self.mailboxes().await {
+                        let summary = match last.get(&mailbox) {
+                            Some(summary) => *summary,
+                            None => self.summary(&mailbox).await,
+                        };
+                        self.notify(summary, |_, sub| sub.mailbox == mailbox && sub.expires <= now).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Response to `req` copying the headers the transaction matches on,
+/// `to` replacing the To when our tag is added.
+fn response(
+    req: &rsip::Request,
+    status_code: rsip::StatusCode,
+    to: Option<&String>,
+    extra: Vec<Header>,
+) -> rsip::Response {
+    let mut headers = req
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::Via(_) | Header::From(_) | Header::CallId(_) | Header::CSeq(_) => Some(h.clone()),
+            Header::To(_) => Some(match to {
+                Some(to) => Header::To(headers::To::new(to.clone())),
+                None => h.clone(),
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    headers.extend(extra);
+    headers.push(Header::ContentLength(headers::ContentLength::new("0")));
+    rsip::Response {
+        status_code,
+        version: rsip::Version::V2,
+        headers: headers.into(),
+        body: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let body = Summary { new: 2, old: 8 }.body("sip:100@192.0.2.1");
+        assert_eq!(
+            body,
+            "Messages-Waiting: yes\r\nMessage-Account: sip:100@192.0.2.1\r\nVoice-Message: 2/8 (0/0)\r\n"
+        );
+        assert!(Summary { new: 0, old: 3 }.body("sip:100@192.0.2.1").starts_with("Messages-Waiting: no\r\n"));
+
+        assert_eq!(granted(None), Some(MAX_EXPIRES));
+        assert_eq!(granted(Some(0)), Some(0));
+        assert_eq!(granted(Some(30)), None);
+        assert_eq!(granted(Some(600)), Some(600));
+        assert_eq!(granted(Some(86400)), Some(MAX_EXPIRES));
+
+        assert_eq!(addr_spec("\"Desk\" <sip:100@192.0.2.9:5060>;expires=600"), "sip:100@192.0.2.9:5060");
+        assert_eq!(addr_spec("sip:100@192.0.2.9;transport=udp"), "sip:100@192.0.2.9");
+    }
+}
@@ -2,24 +2,43 @@ use rsipstack::{
     Error as RsError, Result,
     transport::{SipAddr, udp::UdpConnection},
 };
-use rtp_rs::{RtpPacketBuilder, RtpReader};
+use clap::ValueEnum;
+use rtp_rs::RtpPacketBuilder;
 use std::{
+    collections::VecDeque,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::{select, sync::Mutex};
+use tokio::{select, sync::{Mutex, broadcast}, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{error, info};
 
+use crate::sip::audio_file;
+use crate::sip::call::CallEvent;
+use crate::sip::codec::Codec;
+use crate::sip::dtmf::{DtmfDecoder, ToneDetector};
+use crate::sip::jitter::{Frame, JitterBuffer};
+use crate::sip::latch::{LatchPolicy, MediaLatch, SharedLatch};
+use crate::sip::rtcp::{Reporter, SharedStats, ntp_now};
+use crate::sip::rtp::{RtpPacket, RtpReceiver};
+use crate::sip::sdp::{Negotiated, SessionOffer};
+use crate::sip::srtp::Srtp;
+use crate::sip::vad::Vad;
 use crate::sip::{MediaSessionOption, get_first_non_loopback_interface};
-use crate::web::db::{Pool, Queries, append_chunk_blob, execute};
+use crate::web::db::{DataType, Pool, Quality, Queries, append_chunk_blob, execute, reset_blob};
 
-pub async fn build_rtp_conn(
-    opt: Arc<Mutex<MediaSessionOption>>,
-    ssrc: u32,
-    payload_type: u8,
-) -> anyhow::Result<(UdpConnection, String)> {
+/// room past `max_length` for late frames, 5 s of G.711
+const BLOB_MARGIN: u64 = 8000 * 5;
+
+/// Bytes set aside for a message of at most `max_length` seconds, the
+/// blob grows should it still run over.
+fn blob_size(max_length: u64) -> usize {
+    (max_length * 8000 + BLOB_MARGIN) as usize
+}
+
+/// RTP and RTCP sockets on the first free pair of ports.
+pub async fn bind_rtp(opt: Arc<Mutex<MediaSessionOption>>) -> anyhow::Result<(UdpConnection, UdpConnection)> {
     let addr = get_first_non_loopback_interface()?;
     let mut conn = None;
     let rtp_start_port = opt.lock().await.rtp_start_port;
@@ -27,16 +46,20 @@ pub async fn build_rtp_conn(
     let cancel_token = opt.lock().await.cancel_token.clone();
     for p in 0..100 {
         let port = rtp_start_port + p * 2;
-        if let Ok(c) = UdpConnection::create_connection(
-            format!("{:?}:{}", addr, port).parse()?,
-            external_ip
-                .as_ref()
-                .map(|ip| ip.parse::<SocketAddr>().expect("Invalid external IP")),
-            Some(cancel_token.clone()),
-        )
-        .await
+        let bind = |port: u16| {
+            UdpConnection::create_connection(
+                format!("{:?}:{}", addr, port).parse().expect("RTP address"),
+                external_ip
+                    .as_ref()
+                    .map(|ip| ip.parse::<SocketAddr>().expect("Invalid external IP")),
+                Some(cancel_token.clone()),
+            )
+        };
+        // RTCP goes on the next port up
+        if let Ok(c) = bind(port).await
+            && let Ok(rtcp) = bind(port + 1).await
         {
-            conn = Some(c);
+            conn = Some((c, rtcp));
             break;
         } else {
             info!("Failed to bind RTP socket on port: {}", port);
@@ -49,74 +72,339 @@ pub async fn build_rtp_conn(
         )));
     }
 
-    let conn = conn.unwrap();
-    let codec = payload_type;
-    let codec_name = match codec {
-        0 => "PCMU",
-        // 8 => "PCMA",
-        _ => "Unknown",
-    };
+    Ok(conn.unwrap())
+}
+
+pub async fn build_rtp_conn(
+    opt: Arc<Mutex<MediaSessionOption>>,
+    ssrc: u32,
+    offer: &SessionOffer,
+    media: &Negotiated,
+) -> anyhow::Result<(UdpConnection, UdpConnection, String)> {
+    let (conn, rtcp) = bind_rtp(opt).await?;
     let socketaddr: SocketAddr = conn.get_addr().addr.to_owned().try_into()?;
-    let sdp = format!(
-        "v=0\r\n\
-        o=- 0 0 IN IP4 {}\r\n\
-        s=rsipstack example\r\n\
-        c=IN IP4 {}\r\n\
-        t=0 0\r\n\
-        m=audio {} RTP/AVP {codec}\r\n\
-        a=rtpmap:{codec} {codec_name}/8000\r\n\
-        a=ssrc:{ssrc}\r\n\
-        a=sendrecv\r\n",
-        socketaddr.ip(),
-        socketaddr.ip(),
-        socketaddr.port(),
-    );
-    info!("RTP socket: {:?} {}", conn.get_addr(), sdp);
-    Ok((conn, sdp))
+    let sdp = media.answer(offer, socketaddr, ssrc);
+    info!("RTP socket: {:?} RTCP socket: {:?} {}", conn.get_addr(), rtcp.get_addr(), sdp);
+    Ok((conn, rtcp, sdp))
 }
 
-pub async fn recved_call(pool: &Pool, id: i64, caller: String) -> anyhow::Result<()> {
-    execute(pool, Queries::InsertData(id, caller, vec![0; 300000]))
+/// Row of a new message, its id.
+pub async fn recved_call(
+    pool: &Pool,
+    mailbox: String,
+    caller: String,
+    codec: Codec,
+    max_length: u64,
+) -> anyhow::Result<i64> {
+    let data = vec![0; blob_size(max_length)];
+    let rows = execute(pool, Queries::InsertData(mailbox, caller, codec.name().to_string(), data))
         .await
-        .expect("insert caller");
-    Ok(())
+        .map_err(|e| anyhow::anyhow!("insert caller: {e}"))?;
+    match rows.first() {
+        Some(DataType::Id { id }) => Ok(*id),
+        _ => anyhow::bail!("insert caller: no id"),
+    }
+}
+
+/// How incoming audio is recorded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecordingOption {
+    /// seconds after which the recording is cut off
+    pub max_length: u64,
+    /// messages shorter than this many seconds are deleted
+    pub min_length: u64,
+    /// seconds before the cut-off to warn the caller, 0 to disable
+    pub warning: u64,
+    /// detect DTMF sent as audio
+    pub inband_dtmf: bool,
+    /// blank in-band DTMF tones in the stored message
+    pub strip_dtmf: bool,
+    /// seconds of silence that end the recording, 0 to disable
+    pub silence_timeout: u64,
+    /// delete messages without any speech
+    pub discard_silent: bool,
+    /// cut leading and trailing silence from the message
+    pub trim_silence: bool,
+    /// what skips the greeting and starts recording
+    pub barge_in: BargeIn,
+    /// '*' during the greeting opens the PIN menu
+    pub pin_menu: bool,
+}
+
+/// How a call to `write_pcm` ended.
+pub enum Recorded {
+    /// the message was stored
+    Kept,
+    /// too short or silent, deleted
+    Discarded,
+    /// the caller pressed '*' during the greeting, with the prompt stream
+    Menu(Option<JoinHandle<RtpStream>>),
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BargeIn {
+    Off,
+    #[default]
+    Dtmf,
+    Speech,
+    Any,
+}
+
+impl BargeIn {
+    fn on_dtmf(&self) -> bool {
+        matches!(self, BargeIn::Dtmf | BargeIn::Any)
+    }
+
+    fn on_speech(&self) -> bool {
+        matches!(self, BargeIn::Speech | BargeIn::Any)
+    }
+}
+
+/// packets held back so the start of a tone can be blanked once detected
+const STRIP_DELAY: usize = 3;
+/// silence kept around the speech when trimming, 200 ms
+const TRIM_PAD: u64 = 1600;
+const WARNING_FREQ: f32 = 1400.0;
+const WARNING_MS: u32 = 400;
+/// RFC 3550 minimum report interval
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+
+fn append(pool: &Pool, id: i64, n: u64, dat: &[u8]) -> u64 {
+    match pool.get() {
+        Ok(con) => append_chunk_blob(&con, id, n, dat).unwrap_or(n),
+        Err(e) => {
+            info!("Failed to get connection from pool: {:?}", e);
+            n
+        }
+    }
+}
+
+/// Audio analysis and storage of the message being recorded.
+struct Recorder<'a> {
+    pool: &'a Pool,
+    id: i64,
+    codec: Codec,
+    rec_opt: RecordingOption,
+    vad: Vad,
+    tones: Option<ToneDetector>,
+    delayed: VecDeque<Frame>,
+    /// first and last byte of speech
+    speech: Option<(u64, u64)>,
+    /// bytes of trailing silence
+    silence: u64,
+    /// end of the stored audio
+    end: u64,
+}
+
+impl<'a> Recorder<'a> {
+    fn new(pool: &'a Pool, id: i64, codec: Codec, rec_opt: RecordingOption) -> Self {
+        Self {
+            pool,
+            id,
+            codec,
+            rec_opt,
+            vad: Vad::default(),
+            tones: rec_opt.inband_dtmf.then(ToneDetector::default),
+            delayed: VecDeque::new(),
+            speech: None,
+            silence: 0,
+            end: 0,
+        }
+    }
+
+    /// Store a frame, returns a key if it completed an in-band DTMF tone.
+    fn write(&mut self, frame: Frame) -> Option<char> {
+        let codec = self.codec;
+        let samples = codec.decode_all(&frame.payload);
+        let key = self.tones.as_mut().and_then(|t| t.push(&samples));
+        let len = frame.payload.len() as u64;
+        if !frame.concealed && self.vad.push(&samples) {
+            self.speech = Some((self.speech.map_or(frame.offset, |s| s.0), frame.offset + len));
+            self.silence = 0;
+        } else {
+            self.silence += len;
+        }
+        match self.tones.as_ref() {
+            Some(t) if self.rec_opt.strip_dtmf => {
+                let in_tone = t.in_tone();
+                self.delayed.push_back(frame);
+                if in_tone {
+                    self.delayed
+                        .iter_mut()
+                        .for_each(|f| f.payload.fill(codec.silence()));
+                }
+                while self.delayed.len() > STRIP_DELAY {
+                    if let Some(f) = self.delayed.pop_front() {
+                        self.store(f);
+                    }
+                }
+            }
+            _ => self.store(frame),
+        }
+        key
+    }
+
+    /// Listen for an in-band DTMF tone in audio that isn't recorded, the
+    /// caller talking over the greeting. The detector carries on into the
+    /// recording so a key held across the start is reported once.
+    fn listen(&mut self, payload: &[u8]) -> Option<char> {
+        let samples = self.codec.decode_all(payload);
+        self.tones.as_mut().and_then(|t| t.push(&samples))
+    }
+
+    fn store(&mut self, frame: Frame) {
+        let end = append(self.pool, self.id, frame.offset, &frame.payload);
+        self.end = self.end.max(end);
+    }
+
+    fn flush(&mut self) {
+        while let Some(f) = self.delayed.pop_front() {
+            self.store(f);
+        }
+    }
+
+    fn restart(&mut self) {
+        match self.pool.get() {
+            Ok(con) => {
+                if let Err(e) = reset_blob(&con, self.id, blob_size(self.rec_opt.max_length)) {
+                    info!("Failed to reset recording: {:?}", e);
+                }
+            }
+            Err(e) => info!("Failed to get connection from pool: {:?}", e),
+        }
+        *self = Recorder::new(self.pool, self.id, self.codec, self.rec_opt);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn write_pcm(
     conn: UdpConnection,
     pool: &Pool,
     token: CancellationToken,
     id: i64,
-) -> anyhow::Result<()> {
-    let start = Instant::now();
-    let mut n = 0;
+    media: Negotiated,
+    events: broadcast::Sender<CallEvent>,
+    rec_opt: RecordingOption,
+    mut stream: Option<JoinHandle<RtpStream>>,
+    stats: SharedStats,
+    latch: SharedLatch,
+    prompt: CancellationToken,
+    srtp: Srtp,
+) -> anyhow::Result<Recorded> {
+    let codec = media.codec;
+    let mut start = Instant::now();
+    let mut recording = false;
+    let mut barge_vad = Vad::default();
+    let warn_at = rec_opt.max_length.checked_sub(rec_opt.warning).filter(|_| rec_opt.warning > 0);
+    let mut dtmf = DtmfDecoder::default();
+    let mut jitter = JitterBuffer::new(codec);
+    let mut rec = Recorder::new(pool, id, codec, rec_opt);
+    let mut rx = RtpReceiver::default();
+    let mut mbuf = vec![0; 1500];
+    let mut menu = false;
     select! {
         _ = token.cancelled() => {
             info!("RTP session cancelled");
-            info!("voice length: {n} {id}");
+            info!("voice length: {} {id}", rec.end);
         }
         _ = async {
             loop {
-                let mut mbuf = vec![0; 1500];
                 match conn.recv_raw(&mut mbuf).await {
-                    Ok((len, _)) => {
-                        if let Ok(rtp) = RtpReader::new(&mbuf) {
-                            if rtp.payload_type() != 0 {
-                                continue;
+                    Ok((len, from)) => {
+                        let Some(packet) = srtp.unprotect_rtp(&mbuf[..len]) else {
+                            continue;
+                        };
+                        if let Some(rtp) = rx.accept(&packet) {
+                            if media.accepts(rtp.payload_type) {
+                                observe(&latch, &from);
                             }
-                            let pcmu = rtp.payload();
-                            let dat = &pcmu[..len-12];
-                            // println!("{dat:?}");
-                            let con = pool.get().expect("failed to get connection from pool");
-                            if let Ok(offset) = append_chunk_blob(&con, id, n, dat) {
-                                n = offset;
+                            let dat = rtp.payload;
+                            let audio = rtp.payload_type == codec.payload_type();
+                            if !recording
+                                && audio
+                                && rec_opt.barge_in.on_speech()
+                                && barge_vad.push(&codec.decode_all(dat))
+                            {
+                                info!("barge-in on speech");
+                                prompt.cancel();
+                            }
+                            // the caller is recorded once the greeting and beep are over
+                            if !recording && prompt.is_cancelled() {
+                                info!("recording started");
+                                recording = true;
+                                start = Instant::now();
+                            }
+
+                            let mut keys = vec![];
+                            let ssrc = rtp.ssrc;
+                            if media.dtmf == Some(rtp.payload_type) {
+                                jitter.skip(rtp.sequence);
+                                keys.extend(dtmf.push(rtp.timestamp, dat));
+                            } else if audio && recording {
+                                for frame in jitter.push(rtp.sequence, rtp.timestamp, dat) {
+                                    keys.extend(rec.write(frame));
+                                }
+                            } else if audio {
+                                jitter.skip(rtp.sequence);
+                                keys.extend(rec.listen(dat));
+                            }
+                            if let Ok(mut s) = stats.lock() {
+                                s.remote_ssrc = Some(ssrc);
+                                s.received = jitter.stats;
+                            }
+
+                            for key in keys {
+                                info!("DTMF: {key}");
+                                let _ = events.send(CallEvent::Dtmf(key));
+                                if !recording {
+                                    if key == '*' && rec_opt.pin_menu && stream.is_some() {
+                                        info!("PIN menu requested");
+                                        menu = true;
+                                        return;
+                                    }
+                                    if rec_opt.barge_in.on_dtmf() {
+                                        info!("barge-in on DTMF");
+                                        prompt.cancel();
+                                    }
+                                    continue;
+                                }
+                                match key {
+                                    // finish recording
+                                    '#' => return,
+                                    // record again
+                                    '1' => {
+                                        jitter.restart();
+                                        rec.restart();
+                                        start = Instant::now();
+                                    }
+                                    _ => {}
+                                }
                             }
 
+                            if !recording {
+                                continue;
+                            }
                             let elapsed = start.elapsed();
-                            if 30 < elapsed.as_secs() {
+                            if rec_opt.max_length <= elapsed.as_secs() {
                                 info!("Hung up: {:?}", elapsed);
                                 break;
                             }
+                            if let Some(warn_at) = warn_at
+                                && warn_at <= elapsed.as_secs()
+                                && let Some(s) = stream.take()
+                            {
+                                let tone = codec.tone(WARNING_FREQ, WARNING_MS);
+                                tokio::spawn(async move {
+                                    let Ok(mut s) = s.await else { return };
+                                    if let Err(e) = s.play(&tone).await {
+                                        info!("Failed to play warning tone: {:?}", e);
+                                    }
+                                });
+                            }
+                            if rec_opt.silence_timeout > 0 && rec.silence >= rec_opt.silence_timeout * 8000 {
+                                info!("Hung up on silence: {:?}", elapsed);
+                                break;
+                            }
                         }
                     },
                     Err(e) => {
@@ -126,19 +414,96 @@ pub async fn write_pcm(
                 };
             }
         } => {
-            info!("playback finished, hangup{n}");
+            info!("playback finished, hangup{}", rec.end);
+        }
+    }
+    // stop the greeting if the caller hung up during it
+    prompt.cancel();
+    if menu {
+        if let Err(e) = execute(pool, Queries::DeleteVoicemail(id)).await {
+            info!("Failed to delete message {id}: {e}");
         }
+        return Ok(Recorded::Menu(stream));
     }
+    for frame in jitter.flush() {
+        rec.write(frame);
+    }
+    rec.flush();
 
-    let _ = execute(
-        &pool,
-        Queries::UpdateSampleTime(id,
-            n.checked_div(8).unwrap_or_default()
-        )).await.expect("update sample time");
-    Ok(())
+    let received = jitter.stats;
+    info!(
+        "RTP stats: received {} lost {} late {} duplicates {} concealed {} jitter {:.1} ms",
+        received.received,
+        received.lost(),
+        received.late,
+        received.duplicates,
+        received.concealed,
+        received.jitter_ms()
+    );
+    if rx.malformed > 0 || rx.foreign > 0 {
+        info!("RTP rejected: malformed {} foreign ssrc {}", rx.malformed, rx.foreign);
+    }
+    let media_stats = stats.lock().map(|mut s| {
+        s.received = received;
+        *s
+    }).unwrap_or_default();
+    let quality = Quality {
+        packets: received.received,
+        lost: received.lost(),
+        jitter: received.jitter_ms(),
+        rtt: media_stats.rtt_ms,
+        mos: media_stats.mos(),
+    };
+    info!("call quality {id}: {:?}", quality);
+    if let Err(e) = execute(pool, Queries::UpdateQuality(id, quality)).await {
+        info!("Failed to update call quality {id}: {e}");
+    }
+
+    // offsets follow the RTP timestamps, so this is the real duration
+    let mut n = rec.end;
+    let mut time = n / 8;
+    if time < rec_opt.min_length * 1000 {
+        info!("discard short message {id}: {time} ms");
+        execute(pool, Queries::DeleteVoicemail(id))
+            .await
+            .map_err(|e| anyhow::anyhow!("delete short message: {e}"))?;
+        return Ok(Recorded::Discarded);
+    }
+
+    match rec.speech {
+        None if rec_opt.discard_silent => {
+            info!("discard silent message {id}");
+            execute(pool, Queries::DeleteVoicemail(id))
+                .await
+                .map_err(|e| anyhow::anyhow!("delete silent message: {e}"))?;
+            return Ok(Recorded::Discarded);
+        }
+        Some((first, last)) if rec_opt.trim_silence => {
+            let from = first.saturating_sub(TRIM_PAD);
+            let to = (last + TRIM_PAD).min(n);
+            execute(pool, Queries::TrimBlob(id, from, to - from))
+                .await
+                .map_err(|e| anyhow::anyhow!("trim silence: {e}"))?;
+            n = to - from;
+            time = n / 8;
+        }
+        _ => {}
+    }
+
+    info!("voice length: {n} bytes {time} ms");
+    execute(pool, Queries::UpdateSampleTime(id, time))
+        .await
+        .map_err(|e| anyhow::anyhow!("update sample time: {e}"))?;
+    Ok(Recorded::Kept)
 }
 
-pub async fn play_echo(conn: UdpConnection, token: CancellationToken) -> Result<()> {
+pub async fn play_echo(
+    conn: UdpConnection,
+    token: CancellationToken,
+    media: Negotiated,
+    latch: SharedLatch,
+    srtp: Srtp,
+) -> Result<()> {
     select! {
         _ = token.cancelled() => {
             info!("RTP session cancelled");
@@ -153,7 +518,16 @@ pub async fn play_echo(conn: UdpConnection, token: CancellationToken) -> Result<
                         break;
                     }
                 };
-                match conn.send_raw(&mbuf[..len], &addr).await {
+                let Some(packet) = srtp.unprotect_rtp(&mbuf[..len]) else {
+                    continue;
+                };
+                if RtpPacket::parse(&packet).is_ok_and(|rtp| media.accepts(rtp.payload_type)) {
+                    observe(&latch, &addr);
+                }
+                let Some(packet) = srtp.protect_rtp(&packet).map(|p| p.into_owned()) else {
+                    continue;
+                };
+                match conn.send_raw(&packet, &target(&latch, media.peer)).await {
                     Ok(_) => {},
                     Err(e) => {
                         info!("Failed to send RTP: {:?}", e);
@@ -168,65 +542,276 @@ pub async fn play_echo(conn: UdpConnection, token: CancellationToken) -> Result<
     Ok(())
 }
 
-pub async fn play_audio_file(
+/// Outgoing RTP of a call, keeping sequence numbers and timestamps
+/// continuous across everything played to the caller.
+pub struct RtpStream {
     conn: UdpConnection,
+    latch: SharedLatch,
     ssrc: u32,
-    filename: &str,
-    peer_addr: String,
-    payload_type: u8,
-) -> Result<(u32, u16)> {
-    let mut ts = 0;
-    let mut seq = 1;
+    media: Negotiated,
+    seq: u16,
+    ts: u32,
+    stats: SharedStats,
+    srtp: Srtp,
+}
 
-    select! {
-        _ = async {
-            let peer_addr = SipAddr{
-                addr: peer_addr.try_into().expect("peer_addr"),
-                r#type: Some(rsip::transport::Transport::Udp),
-            };
-            let sample_size = 160;
-            let mut ticker = tokio::time::interval(Duration::from_millis(20));
-            let ext = match payload_type {
-                // 8 => "pcma",
-                0 => "pcmu",
-                _ => {
-                    info!("Unsupported codec type: {}", payload_type);
-                    return;
+fn sip_addr(addr: SocketAddr) -> SipAddr {
+    SipAddr {
+        addr: addr.to_string().try_into().expect("peer_addr"),
+        r#type: Some(rsip::transport::Transport::Udp),
+    }
+}
+
+/// Move the send destination to `from` if the latching policy allows it.
+pub fn observe(latch: &SharedLatch, from: &SipAddr) {
+    let Ok(from) = SocketAddr::try_from(from.addr.to_owned()) else {
+        return;
+    };
+    if let Ok(mut l) = latch.lock()
+        && l.observe(from)
+    {
+        info!("Media latched to {from}");
+    }
+}
+
+pub fn target(latch: &SharedLatch, sdp: SocketAddr) -> SipAddr {
+    sip_addr(latch.lock().map(|l| l.target()).unwrap_or(sdp))
+}
+
+impl RtpStream {
+    pub fn new(
+        conn: UdpConnection,
+        ssrc: u32,
+        media: Negotiated,
+        stats: SharedStats,
+        latch: SharedLatch,
+        srtp: Srtp,
+    ) -> Self {
+        Self { conn, latch, ssrc, media, seq: 1, ts: 0, stats, srtp }
+    }
+
+    /// SRTP of the call, for whoever receives its media.
+    pub fn srtp(&self) -> Srtp {
+        self.srtp.clone()
+    }
+
+    /// Send `data` in packets of ptime, paced in real time.
+    pub async fn play(&mut self, data: &[u8]) -> Result<()> {
+        let mut ticker = tokio::time::interval(Duration::from_millis(self.media.ptime as u64));
+        for chunk in data.chunks(self.media.samples()) {
+            let result = match RtpPacketBuilder::new()
+            .payload_type(self.media.codec.payload_type())
+            .ssrc(self.ssrc)
+            .sequence(self.seq.into())
+            .timestamp(self.ts)
+            .payload(chunk)
+            .build() {
+                Ok(r) => r,
+                Err(e) => {
+                    info!("Failed to build RTP packet: {:?}", e);
+                    break;
                 }
             };
-            let file_name = format!("./assets/{filename}.{ext}");
-            info!("Playing {filename} file: {} payload_type:{} sample_size:{}",
-                file_name, payload_type, sample_size);
-            let example_data = tokio::fs::read(file_name).await.expect("read file");
-
-            for chunk in example_data.chunks(sample_size) {
-                let result = match RtpPacketBuilder::new()
-                .payload_type(payload_type)
-                .ssrc(ssrc)
-                .sequence(seq.into())
-                .timestamp(ts)
-                .payload(chunk)
-                .build() {
+            self.ts = self.ts.wrapping_add(chunk.len() as u32);
+            self.seq = self.seq.wrapping_add(1);
+            let Some(result) = self.srtp.protect_rtp(&result) else {
+                break;
+            };
+            let peer = target(&self.latch, self.media.peer);
+            if let Err(e) = self.conn.send_raw(&result, &peer).await {
+                info!("Failed to send RTP: {:?}", e);
+                return Err(e);
+            }
+            if let Ok(mut s) = self.stats.lock() {
+                s.sent_packets = s.sent_packets.wrapping_add(1);
+                s.sent_octets = s.sent_octets.wrapping_add(chunk.len() as u32);
+                s.last_ts = self.ts;
+            }
+            ticker.tick().await;
+        }
+        Ok(())
+    }
+}
+
+/// Exchange RTCP reports with the caller until the media ends.
+pub async fn rtcp_session(
+    conn: UdpConnection,
+    media: Negotiated,
+    ssrc: u32,
+    stats: SharedStats,
+    policy: LatchPolicy,
+    srtp: Srtp,
+    token: CancellationToken,
+) {
+    let sdp = SocketAddr::new(media.peer.ip(), media.peer.port() + 1);
+    let latch = MediaLatch::shared(policy, sdp);
+    let cname = format!("voicemail@{}", conn.get_addr().addr);
+    let mut reporter = Reporter::new(ssrc, cname);
+    let mut ticker = tokio::time::interval(RTCP_INTERVAL);
+    let mut mbuf = vec![0; 1500];
+    loop {
+        select! {
+            _ = token.cancelled() => {
+                let snapshot = stats.lock().map(|s| *s).unwrap_or_default();
+                let bye = reporter.bye(&snapshot, ntp_now());
+                if let Some(bye) = srtp.protect_rtcp(&bye)
+                    && let Err(e) = conn.send_raw(&bye, &target(&latch, sdp)).await
+                {
+                    info!("Failed to send RTCP BYE: {:?}", e);
+                }
+                if let Some(r) = snapshot.remote {
+                    info!(
+                        "caller reported: fraction lost {}/256 lost {} highest seq {} jitter {} rtt {:?} ms",
+                        r.fraction_lost, r.cumulative_lost, r.highest_seq, r.jitter, snapshot.rtt_ms
+                    );
+                }
+                break;
+            }
+            _ = ticker.tick() => {
+                let snapshot = stats.lock().map(|s| *s).unwrap_or_default();
+                let report = reporter.report(&snapshot, ntp_now());
+                if let Some(report) = srtp.protect_rtcp(&report)
+                    && let Err(e) = conn.send_raw(&report, &target(&latch, sdp)).await
+                {
+                    info!("Failed to send RTCP: {:?}", e);
+                }
+            }
+            r = conn.recv_raw(&mut mbuf) => {
+                let (len, from) = match r {
                     Ok(r) => r,
                     Err(e) => {
-                        info!("Failed to build RTP packet: {:?}", e);
+                        info!("Failed to receive RTCP: {:?}", e);
                         break;
                     }
                 };
-                ts += chunk.len() as u32;
-                seq += 1;
-                match conn.send_raw(&result, &peer_addr).await {
-                    Ok(_) => {},
-                    Err(e) => {
-                        info!("Failed to send RTP: {:?}", e);
-                        break;
+                let Some(packet) = srtp.unprotect_rtcp(&mbuf[..len]) else {
+                    continue;
+                };
+                let result = match stats.lock() {
+                    Ok(mut s) => reporter.receive(&packet, &mut s, ntp_now()),
+                    Err(_) => break,
+                };
+                match result {
+                    Ok(true) => observe(&latch, &from),
+                    Ok(false) => info!("RTCP BYE from caller"),
+                    Err(e) => info!("Invalid RTCP packet: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Tone telling the caller that recording starts.
+pub async fn play_beep(stream: &mut RtpStream, freq: f32, ms: u32) -> Result<()> {
+    let beep = stream.media.codec.tone(freq, ms);
+    info!("Playing beep: {freq} Hz {ms} ms");
+    stream.play(&beep).await
+}
+
+/// What is played before the beep.
+#[derive(Debug, Clone)]
+pub enum Greeting {
+    /// a name or path for `audio_file::load`
+    File(String),
+    /// text rendered to 8 kHz μ-law
+    Speech(Vec<u8>),
+}
+
+pub async fn play_greeting(stream: &mut RtpStream, greeting: &Greeting) -> Result<()> {
+    match greeting {
+        Greeting::File(name) => play_audio_file(stream, name).await,
+        Greeting::Speech(pcmu) => {
+            let codec = stream.media.codec;
+            let data = codec.transcode(Codec::Pcmu, pcmu);
+            info!("Playing speech: codec:{} bytes:{}", codec, data.len());
+            if let Err(e) = stream.play(&data).await {
+                info!("play speech failed: {:?}", e);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Play the greeting and beep while `write_pcm` listens, stopping early
+/// when `done` is cancelled by a barge-in. Cancels `done` when finished.
+pub fn play_prompt(
+    mut stream: RtpStream,
+    greeting: Option<Greeting>,
+    beep: Option<(f32, u32)>,
+    done: CancellationToken,
+) -> JoinHandle<RtpStream> {
+    tokio::spawn(async move {
+        select! {
+            _ = done.cancelled() => {
+                info!("prompt interrupted");
+            }
+            _ = async {
+                if let Some(greeting) = greeting
+                    && let Err(e) = play_greeting(&mut stream, &greeting).await
+                {
+                    info!("Failed to play greeting: {:?}", e);
+                }
+                if let Some((freq, ms)) = beep
+                    && let Err(e) = play_beep(&mut stream, freq, ms).await
+                {
+                    info!("Failed to play beep: {:?}", e);
+                }
+            } => {}
+        }
+        done.cancel();
+        stream
+    })
+}
+
+/// Consume inbound RTP while only sending, so media latching still works
+/// and packets do not pile up in the socket.
+pub async fn listen(
+    conn: UdpConnection,
+    media: Negotiated,
+    latch: SharedLatch,
+    srtp: Srtp,
+    token: CancellationToken,
+) {
+    let mut mbuf = vec![0; 1500];
+    loop {
+        select! {
+            _ = token.cancelled() => break,
+            r = conn.recv_raw(&mut mbuf) => match r {
+                Ok((len, from)) => {
+                    if srtp
+                        .unprotect_rtp(&mbuf[..len])
+                        .is_some_and(|p| RtpPacket::parse(&p).is_ok_and(|rtp| media.accepts(rtp.payload_type)))
+                    {
+                        observe(&latch, &from);
                     }
                 }
-                ticker.tick().await;
+                Err(e) => {
+                    info!("Failed to receive RTP: {:?}", e);
+                    break;
+                }
             }
-        } => {
-            info!("play audio file finished");
         }
     }
-    Ok((ts, seq))
+}
+
+pub async fn play_audio_file(stream: &mut RtpStream, filename: &str) -> Result<()> {
+    let codec = stream.media.codec;
+    let name = filename.to_owned();
+    let example_data = match tokio::task::spawn_blocking(move || audio_file::load(&name, codec)).await {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => {
+            error!("{e}");
+            return Ok(());
+        }
+        Err(e) => {
+            error!("Failed to load {filename}: {e}");
+            return Ok(());
+        }
+    };
+    info!("Playing {filename} file: codec:{} sample_size:{}", codec, stream.media.samples());
+    if let Err(e) = stream.play(&example_data).await {
+        info!("play audio file failed: {:?}", e);
+    }
+    info!("play audio file finished");
+    Ok(())
 }
@@ -0,0 +1,462 @@
+//! Keeping the registration up: refreshing it before it expires,
+//! moving on to the next server the DNS names when one does not answer,
+//! retrying with backoff while the registrar fails, and its state for
+//! the web UI.
+
+use crate::sip::account::Account;
+use crate::sip::message::header;
+use crate::sip::resolve::{Resolver, Target, resolve};
+use crate::sip::transport;
+use anyhow::{Result, bail};
+use rsip::{Header, Transport, headers};
+use rsipstack::{
+    dialog::authenticate::{Credential, handle_client_authenticate},
+    transaction::{
+        endpoint::EndpointInnerRef,
+        key::{TransactionKey, TransactionRole},
+        transaction::Transaction,
+    },
+    transport::SipAddr,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::RwLock,
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// first retry after a failure, doubled on each failure after it
+const MIN_RETRY: Duration = Duration::from_secs(2);
+const MAX_RETRY: Duration = Duration::from_secs(300);
+/// share of the granted expiry after which we register again
+const REFRESH: f64 = 0.8;
+/// least expiry asked for
+const MIN_EXPIRES: u32 = 60;
+/// least wait before refreshing, however short the grant
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+/// expiry asked for
+const EXPIRES: u32 = 3600;
+/// a REGISTER unanswered for this long moves on to the next target,
+/// 64*T1 as the transaction itself
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(32);
+
+/// Where a registration stands, as shown in the browser.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegState {
+    #[default]
+    Registering,
+    Registered,
+    /// the last REGISTER failed, another one is waiting
+    Retrying,
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    mailbox: String,
+    state: RegState,
+    target: Option<String>,
+    expires: Option<Instant>,
+    retry: Option<Instant>,
+    failures: u32,
+    last_error: Option<String>,
+}
+
+/// Snapshot of an account's registration for the web api.
+#[derive(Debug, Serialize)]
+pub struct RegInfo {
+    /// address of record
+    pub account: String,
+    pub mailbox: String,
+    pub state: RegState,
+    /// address and transport registered over
+    pub target: Option<String>,
+    /// seconds until the binding expires
+    pub expires: Option<u64>,
+    /// seconds until the next attempt after a failure
+    pub retry: Option<u64>,
+    /// failures in a row
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Registration state of each account, keyed by address of record,
+/// shared with the web server.
+#[derive(Debug, Clone, Default)]
+pub struct RegStatus(Arc<RwLock<BTreeMap<String, Status>>>);
+
+impl RegStatus {
+    pub async fn list(&self) -> Vec<RegInfo> {
+        let left = |at: Option<Instant>| at.map(|at| at.saturating_duration_since(Instant::now()).as_secs());
+        self.0
+            .read()
+            .await
+            .iter()
+            .map(|(account, s)| RegInfo {
+                account: account.clone(),
+                mailbox: s.mailbox.clone(),
+                state: s.state,
+                target: s.target.clone(),
+                expires: left(s.expires),
+                retry: left(s.retry),
+                failures: s.failures,
+                last_error: s.last_error.clone(),
+            })
+            .collect()
+    }
+
+    async fn update(&self, account: &str, f: impl FnOnce(&mut Status)) {
+        f(self.0.write().await.entry(account.to_owned()).or_default())
+    }
+}
+
+/// Exponential backoff with jitter.
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    /// Delay before the next attempt, `jitter` in 0..1 spreading it
+    /// over the upper half of the backoff.
+    fn next(&mut self, jitter: f64) -> Duration {
+        let base = MIN_RETRY.saturating_mul(1 << self.failures.min(16)).min(MAX_RETRY);
+        self.failures += 1;
+        base.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// When to register again after `granted` seconds were granted.
+fn refresh(granted: u32) -> Duration {
+    Duration::from_secs(granted.into()).mul_f64(REFRESH).max(MIN_REFRESH)
+}
+
+/// Seconds at the start of a Retry-After or Min-Expires value, comments
+/// and parameters ignored.
+fn seconds(value: &str) -> Option<u32> {
+    let digits = value.trim().split(|c: char| !c.is_ascii_digit()).next()?;
+    digits.parse().ok()
+}
+
+/// Seconds the registrar granted, from the expires parameter of our
+/// binding among its Contacts, else its Expires header.
+fn granted(resp: &rsip::Response, contact: &rsip::Uri, requested: u32) -> u32 {
+    let ours = contact.host_with_port.to_string();
+    let binding = resp
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::Contact(c) => Some(c.to_string()),
+            _ => None,
+        })
+        .flat_map(|c| c.split(',').map(str::to_owned).collect::<Vec<_>>())
+        .find(|c| c.contains(&ours));
+    binding
+        .and_then(|c| {
+            c.split(';').find_map(|p| {
+                let (name, value) = p.split_once('=')?;
+                name.trim().eq_ignore_ascii_case("expires").then(|| seconds(value))?
+            })
+        })
+        .or_else(|| header(&resp.headers, "Expires").and_then(|v| seconds(&v)))
+        .unwrap_or(requested)
+}
+
+/// REGISTER transactions of one account. The Request-URI and address of
+/// record keep the domain, only the destination is the target tried.
+struct Registrar {
+    endpoint: EndpointInnerRef,
+    account: Account,
+    credential: Credential,
+    /// our listeners, the one of the target's transport sends
+    listeners: Vec<SipAddr>,
+    call_id: String,
+    tag: String,
+    cseq: u32,
+}
+
+impl Registrar {
+    fn new(endpoint: EndpointInnerRef, account: Account, listeners: Vec<SipAddr>) -> Self {
+        Self {
+            endpoint,
+            credential: account.credential(),
+            account,
+            listeners,
+            call_id: format!("{:032x}", rand::random::<u128>()),
+            tag: format!("{:016x}", rand::random::<u64>()),
+            cseq: 0,
+        }
+    }
+
+    /// Our contact over `transport`.
+    fn contact(&self, transport: Transport) -> Result<rsip::Uri> {
+        let local = self
+            .listeners
+            .iter()
+            .find(|a| a.r#type == Some(transport))
+            .or(self.listeners.first())
+            .ok_or_else(|| anyhow::anyhow!("no address found"))?;
+        Ok(transport::contact(self.account.user.clone(), local.addr.clone(), transport))
+    }
+
+    fn request(&mut self, contact: &rsip::Uri, transport: Transport, expires: u32) -> rsip::Request {
+        self.cseq += 1;
+        let aor = self.account.aor();
+        let via = format!(
+            "SIP/2.0/{transport} {};branch=z9hG4bK{:016x};rport",
+            contact.host_with_port,
+            rand::random::<u64>()
+        );
+        let headers: Vec<Header> = vec![
+            Header::Via(headers::Via::new(via)),
+            Header::MaxForwards(headers::MaxForwards::new("70")),
+            Header::From(headers::From::new(format!("<{aor}>;tag={}", self.tag))),
+            Header::To(headers::To::new(format!("<{aor}>"))),
+            Header::CallId(headers::CallId::new(&self.call_id)),
+            Header::CSeq(headers::CSeq::new(format!("{} REGISTER", self.cseq))),
+            Header::Contact(headers::Contact::new(format!("<{contact}>"))),
+            Header::Expires(headers::Expires::new(expires.to_string())),
+            Header::ContentLength(headers::ContentLength::new("0")),
+        ];
+        rsip::Request {
+            method: rsip::Method::Register,
+            uri: self.account.server.clone(),
+            version: rsip::Version::V2,
+            headers: headers.into(),
+            body: vec![],
+        }
+    }
+
+    /// REGISTER at `target`, or wherever the server URI leads when
+    /// nothing resolved, answering one authentication challenge. The
+    /// final response and the seconds granted.
+    async fn register(&mut self, target: Option<&Target>, expires: u32) -> Result<(rsip::Response, u32)> {
+        let transport = target.map_or(transport::of_uri(&self.account.server), |t| t.transport);
+        let destination = target.map(Target::destination);
+        let contact = self.contact(transport)?;
+        let req = self.request(&contact, transport, expires);
+        let key = TransactionKey::from_request(&req, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, req, self.endpoint.clone(), None);
+        tx.destination = destination.clone();
+        tx.send().await?;
+        let mut challenged = false;
+        while let Some(msg) = tx.receive().await {
+            let rsip::SipMessage::Response(resp) = msg else {
+                continue;
+            };
+            match resp.status_code {
+                rsip::StatusCode::Unauthorized | rsip::StatusCode::ProxyAuthenticationRequired if !challenged => {
+                    challenged = true;
+                    self.cseq += 1;
+                    tx = handle_client_authenticate(self.cseq, tx, resp, &self.credential).await?;
+                    tx.destination = destination.clone();
+                    tx.send().await?;
+                }
+                code if code.code() < 200 => {}
+                _ => {
+                    let granted = granted(&resp, &contact, expires);
+                    return Ok((resp, granted));
+                }
+            }
+        }
+        bail!("no response")
+    }
+}
+
+/// Keep every account registered until cancelled.
+pub async fn process_registrations(
+    endpoint: EndpointInnerRef,
+    accounts: Vec<Account>,
+    resolver: Arc<Resolver>,
+    listeners: Vec<SipAddr>,
+    status: RegStatus,
+    cancel_token: CancellationToken,
+) -> Result<()> {
+    let mut registrations = JoinSet::new();
+    for account in accounts {
+        let (endpoint, status, token) = (endpoint.clone(), status.clone(), cancel_token.clone());
+        let (resolver, listeners) = (resolver.clone(), listeners.clone());
+        registrations.spawn(process_registration(endpoint, account, resolver, listeners, status, token));
+    }
+    registrations.join_all().await;
+    cancel_token.cancelled().await;
+    Ok(())
+}
+
+/// Register `account` until cancelled, never giving up on a failing
+/// registrar.
+async fn process_registration(
+    endpoint: EndpointInnerRef,
+    account: Account,
+    resolver: Arc<Resolver>,
+    listeners: Vec<SipAddr>,
+    status: RegStatus,
+    cancel_token: CancellationToken,
+) {
+    let aor = account.aor();
+    let server = account.server.clone();
+    status.update(&aor, |s| s.mailbox = account.mailbox().to_owned()).await;
+
+    let supported = listeners.iter().filter_map(|a| a.r#type).collect::<Vec<_>>();
+    let mut registrar = Registrar::new(endpoint, account, listeners);
+    let mut backoff = Backoff::default();
+    // raised by 423 Interval Too Brief
+    let mut expires = None;
+    // servers for the account, best first, looked up again after all
+    // of them failed
+    let mut targets: Vec<Target> = vec![];
+    let mut current = 0;
+    loop {
+        if targets.is_empty() {
+            targets = resolve(&*resolver, &server, &supported).await;
+            current = 0;
+            debug!("{aor} resolves to {targets:?}");
+        }
+        // left to the transport layer when nothing resolved
+        let target = targets.get(current);
+        let shown = target.map(|t| format!("{} {}", t.transport, t.addr));
+        status
+            .update(&aor, |s| {
+                s.state = RegState::Registering;
+                s.target = shown;
+            })
+            .await;
+        let requested = expires.unwrap_or(EXPIRES).max(MIN_EXPIRES);
+        let result = match timeout(REGISTER_TIMEOUT, registrar.register(target, requested)).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err("timed out".to_owned()),
+        };
+        let unreachable = match &result {
+            Ok((resp, _)) => resp.status_code == rsip::StatusCode::ServiceUnavailable
+                && header(&resp.headers, "Retry-After").is_none(),
+            Err(_) => true,
+        };
+        if unreachable && current + 1 < targets.len() {
+            info!("registration of {aor} at {} failed, trying the next server", targets[current].addr);
+            current += 1;
+            continue;
+        }
+        if unreachable {
+            targets.clear();
+        }
+        let outcome = match result {
+            Ok((resp, granted)) => {
+                debug!("received response: {}", resp.to_string());
+                match resp.status_code {
+                    rsip::StatusCode::OK => Ok(granted),
+                    rsip::StatusCode::IntervalTooBrief => {
+                        let min = header(&resp.headers, "Min-Expires").and_then(|v| seconds(&v));
+                        match min {
+                            // again at once with what the registrar asks for
+                            Some(min) if expires.is_none_or(|e| e < min) => {
+                                info!("registrar wants at least {min} s");
+                                expires = Some(min);
+                                continue;
+                            }
+                            _ => Err((resp.status_code.to_string(), None)),
+                        }
+                    }
+                    code => {
+                        let after = header(&resp.headers, "Retry-After").and_then(|v| seconds(&v));
+                        Err((code.to_string(), after))
+                    }
+                }
+            }
+            Err(e) => Err((e, None)),
+        };
+
+        let wait = match outcome {
+            Ok(granted) => {
+                backoff = Backoff::default();
+                info!("registered {aor} for {granted} s");
+                status
+                    .update(&aor, |s| {
+                        s.state = RegState::Registered;
+                        s.expires = Some(Instant::now() + Duration::from_secs(granted.into()));
+                        s.retry = None;
+                        s.failures = 0;
+                    })
+                    .await;
+                refresh(granted)
+            }
+            Err((error, after)) => {
+                let wait = match after {
+                    Some(after) => {
+                        backoff.failures += 1;
+                        Duration::from_secs(after.into())
+                    }
+                    None => backoff.next(rand::random()),
+                };
+                info!("registration of {aor} failed: {error}, retrying in {wait:?}");
+                status
+                    .update(&aor, |s| {
+                        s.state = RegState::Retrying;
+                        s.retry = Some(Instant::now() + wait);
+                        s.failures = backoff.failures;
+                        s.last_error = Some(error);
+                    })
+                    .await;
+                wait
+            }
+        };
+        select! {
+            _ = cancel_token.cancelled() => return,
+            _ = sleep(wait) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next(1.0), Duration::from_secs(2));
+        assert_eq!(backoff.next(1.0), Duration::from_secs(4));
+        assert_eq!(backoff.next(0.0), Duration::from_secs(4));
+        for _ in 0..40 {
+            assert!(backoff.next(1.0) <= MAX_RETRY);
+        }
+        assert_eq!(backoff.next(0.0), MAX_RETRY / 2);
+        assert_eq!(backoff.failures, 44);
+
+        assert_eq!(refresh(3600), Duration::from_secs(2880));
+        // a short grant is refreshed before it lapses
+        assert_eq!(refresh(30), Duration::from_secs(24));
+        assert_eq!(refresh(0), MIN_REFRESH);
+
+        assert_eq!(seconds("120"), Some(120));
+        assert_eq!(seconds(" 18000 (planned maintenance);duration=3600"), Some(18000));
+        assert_eq!(seconds("soon"), None);
+    }
+    #[test]
+    fn test_granted() {
+        let resp = |headers: Vec<Header>| rsip::Response {
+            status_code: rsip::StatusCode::OK,
+            version: rsip::Version::V2,
+            headers: headers.into(),
+            body: vec![],
+        };
+        let contact = rsip::Uri::try_from("sip:100@198.51.100.7:5060").unwrap();
+        let ok = resp(vec![
+            Header::Contact(headers::Contact::new(
+                "<sip:100@192.0.2.9:5060>;expires=600, <sip:100@198.51.100.7:5060>;expires=120",
+            )),
+            Header::Expires(headers::Expires::new("300")),
+        ]);
+        assert_eq!(granted(&ok, &contact, 3600), 120);
+        let other = rsip::Uri::try_from("sip:100@203.0.113.1:5060").unwrap();
+        assert_eq!(granted(&ok, &other, 3600), 300);
+        assert_eq!(granted(&resp(vec![]), &contact, 3600), 3600);
+    }
+}
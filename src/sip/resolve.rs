@@ -0,0 +1,391 @@
+//! Locating the SIP server as in RFC 3263: NAPTR picks the transport,
+//! SRV the hosts and ports in priority and weight order, A and AAAA
+//! their addresses.
+
+use anyhow::Result;
+use hickory_resolver::{
+    TokioResolver,
+    config::{NameServerConfigGroup, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::rr::{RData, RecordType},
+};
+use rsip::{Host, Param, Scheme, Transport};
+use rsipstack::transport::SipAddr;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Naptr {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: String,
+    pub service: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Lookups the resolution needs, empty when a name has no records.
+pub trait Dns {
+    fn naptr(&self, name: &str) -> impl Future<Output = Vec<Naptr>> + Send;
+    fn srv(&self, name: &str) -> impl Future<Output = Vec<Srv>> + Send;
+    fn ip(&self, host: &str) -> impl Future<Output = Vec<IpAddr>> + Send;
+}
+
+/// The system resolver, or the name server given, ex. a local stand-in
+/// serving test records.
+pub struct Resolver(TokioResolver);
+
+impl Resolver {
+    pub fn new(server: Option<SocketAddr>) -> Result<Self> {
+        let builder = match server {
+            Some(addr) => {
+                let group = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                TokioResolver::builder_with_config(
+                    ResolverConfig::from_parts(None, vec![], group),
+                    TokioConnectionProvider::default(),
+                )
+            }
+            None => TokioResolver::builder_tokio()?,
+        };
+        Ok(Self(builder.build()))
+    }
+}
+
+impl Dns for Resolver {
+    async fn naptr(&self, name: &str) -> Vec<Naptr> {
+        match self.0.lookup(name, RecordType::NAPTR).await {
+            Ok(lookup) => lookup
+                .iter()
+                .filter_map(|r| match r {
+                    RData::NAPTR(n) => Some(Naptr {
+                        order: n.order(),
+                        preference: n.preference(),
+                        flags: String::from_utf8_lossy(n.flags()).into_owned(),
+                        service: String::from_utf8_lossy(n.services()).into_owned(),
+                        replacement: n.replacement().to_utf8(),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            Err(e) => {
+                debug!("NAPTR {name}: {e}");
+                vec![]
+            }
+        }
+    }
+
+    async fn srv(&self, name: &str) -> Vec<Srv> {
+        match self.0.srv_lookup(name).await {
+            Ok(lookup) => lookup
+                .iter()
+                .map(|s| Srv {
+                    priority: s.priority(),
+                    weight: s.weight(),
+                    port: s.port(),
+                    target: s.target().to_utf8(),
+                })
+                .collect(),
+            Err(e) => {
+                debug!("SRV {name}: {e}");
+                vec![]
+            }
+        }
+    }
+
+    async fn ip(&self, host: &str) -> Vec<IpAddr> {
+        match self.0.lookup_ip(host).await {
+            Ok(lookup) => lookup.iter().collect(),
+            Err(e) => {
+                debug!("A/AAAA {host}: {e}");
+                vec![]
+            }
+        }
+    }
+}
+
+/// Where to send requests for the server, in the order to try them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub transport: Transport,
+    pub addr: SocketAddr,
+    /// the name `addr` was found under, without the trailing dot
+    pub host: String,
+}
+
+impl Target {
+    /// Transaction destination: the address, but over TLS the host name
+    /// and port, so the certificate is checked against the name the DNS
+    /// gave and not an address.
+    pub fn destination(&self) -> SipAddr {
+        let host = match (self.transport, self.host.parse::<IpAddr>()) {
+            (Transport::Tls, Err(_)) => Host::Domain(self.host.clone().into()),
+            _ => Host::IpAddr(self.addr.ip()),
+        };
+        SipAddr {
+            r#type: Some(self.transport),
+            addr: rsip::HostWithPort {
+                host,
+                port: Some(self.addr.port().into()),
+            },
+        }
+    }
+}
+
+/// NAPTR service field and SRV prefix of `transport`.
+fn service(transport: Transport) -> Option<(&'static str, &'static str)> {
+    match transport {
+        Transport::Udp => Some(("SIP+D2U", "_sip._udp")),
+        Transport::Tcp => Some(("SIP+D2T", "_sip._tcp")),
+        Transport::Tls => Some(("SIPS+D2T", "_sips._tcp")),
+        _ => None,
+    }
+}
+
+fn default_port(transport: Transport) -> u16 {
+    match transport {
+        Transport::Tls => 5061,
+        _ => 5060,
+    }
+}
+
+/// SRV records in the order to try them: by priority, and within one
+/// by a weighted draw as in RFC 2782, `draw(total)` picking 0..=total.
+fn order_srv(mut records: Vec<Srv>, mut draw: impl FnMut(u32) -> u32) -> Vec<Srv> {
+    records.sort_by_key(|r| (r.priority, r.weight));
+    let mut ordered = Vec::with_capacity(records.len());
+    while let Some(first) = records.first() {
+        let priority = first.priority;
+        let mut group = records.iter().take_while(|r| r.priority == priority).count();
+        while group > 0 {
+            let total = records[..group].iter().map(|r| u32::from(r.weight)).sum::<u32>();
+            let pick = draw(total);
+            let mut sum = 0;
+            let i = records[..group]
+                .iter()
+                .position(|r| {
+                    sum += u32::from(r.weight);
+                    sum >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(records.remove(i));
+            group -= 1;
+        }
+    }
+    ordered
+}
+
+/// Targets of `server` over the `supported` transports, best first.
+/// Empty when nothing resolves.
+pub async fn resolve(dns: &impl Dns, server: &rsip::Uri, supported: &[Transport]) -> Vec<Target> {
+    let secure = server.scheme == Some(Scheme::Sips);
+    let port = server.host_with_port.port.map(|p| *p.value());
+    let explicit = server.params.iter().find_map(|p| match p {
+        Param::Transport(t) => Some(*t),
+        _ => None,
+    });
+    let fallback = explicit.unwrap_or(if secure { Transport::Tls } else { Transport::Udp });
+    let host = match &server.host_with_port.host {
+        Host::IpAddr(ip) => {
+            let addr = SocketAddr::new(*ip, port.unwrap_or(default_port(fallback)));
+            return vec![Target { transport: fallback, addr, host: ip.to_string() }];
+        }
+        Host::Domain(domain) => domain.to_string(),
+    };
+    let addresses = async |host: &str, port: u16, transport: Transport| {
+        let ips = dns.ip(host).await;
+        let host = host.trim_end_matches('.').to_owned();
+        ips.into_iter().map(move |ip| Target {
+            transport,
+            addr: SocketAddr::new(ip, port),
+            host: host.clone(),
+        })
+    };
+    if let Some(port) = port {
+        return addresses(&host, port, fallback).await.collect();
+    }
+
+    let usable = |t: Transport| supported.contains(&t) && (!secure || t == Transport::Tls);
+    // SRV names to try with their transport
+    let services = match explicit {
+        Some(t) => service(t).map(|(_, prefix)| vec![(t, format!("{prefix}.{host}"))]).unwrap_or_default(),
+        None => {
+            let mut naptr = dns.naptr(&host).await;
+            naptr.sort_by_key(|n| (n.order, n.preference));
+            let from_naptr = naptr
+                .into_iter()
+                .filter(|n| n.flags.eq_ignore_ascii_case("s"))
+                .filter_map(|n| {
+                    let t = [Transport::Udp, Transport::Tcp, Transport::Tls].into_iter().find(|t| {
+                        service(*t).is_some_and(|(s, _)| s.eq_ignore_ascii_case(&n.service))
+                    })?;
+                    usable(t).then_some((t, n.replacement))
+                })
+                .collect::<Vec<_>>();
+            match from_naptr.is_empty() {
+                false => from_naptr,
+                true => [Transport::Udp, Transport::Tcp, Transport::Tls]
+                    .into_iter()
+                    .filter(|t| usable(*t))
+                    .filter_map(|t| service(t).map(|(_, prefix)| (t, format!("{prefix}.{host}"))))
+                    .collect(),
+            }
+        }
+    };
+
+    let mut targets = vec![];
+    let mut found = false;
+    for (transport, name) in services {
+        let records = dns.srv(&name).await;
+        found |= !records.is_empty();
+        for srv in order_srv(records, |total| rand::random_range(0..=total)) {
+            // "." means the service is not offered there
+            if srv.target.trim_end_matches('.').is_empty() {
+                continue;
+            }
+            targets.extend(addresses(&srv.target, srv.port, transport).await);
+        }
+    }
+    if !found {
+        targets.extend(addresses(&host, default_port(fallback), fallback).await);
+    }
+    let mut seen = vec![];
+    targets.retain(|t| {
+        let new = !seen.contains(t);
+        seen.push(t.clone());
+        new
+    });
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Records served in place of a name server.
+    #[derive(Default)]
+    struct StandIn {
+        naptr: HashMap<&'static str, Vec<Naptr>>,
+        srv: HashMap<&'static str, Vec<Srv>>,
+        ip: HashMap<&'static str, Vec<IpAddr>>,
+    }
+
+    impl Dns for StandIn {
+        async fn naptr(&self, name: &str) -> Vec<Naptr> {
+            self.naptr.get(name).cloned().unwrap_or_default()
+        }
+
+        async fn srv(&self, name: &str) -> Vec<Srv> {
+            self.srv.get(name).cloned().unwrap_or_default()
+        }
+
+        async fn ip(&self, host: &str) -> Vec<IpAddr> {
+            self.ip.get(host).cloned().unwrap_or_default()
+        }
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Srv {
+        Srv { priority, weight, port, target: target.to_owned() }
+    }
+
+    fn stand_in() -> StandIn {
+        let mut dns = StandIn::default();
+        dns.naptr.insert("example.com", vec![
+            Naptr {
+                order: 20,
+                preference: 10,
+                flags: "S".into(),
+                service: "SIP+D2U".into(),
+                replacement: "_sip._udp.example.com.".into(),
+            },
+            Naptr {
+                order: 10,
+                preference: 10,
+                flags: "S".into(),
+                service: "SIPS+D2T".into(),
+                replacement: "_sips._tcp.example.com.".into(),
+            },
+        ]);
+        dns.srv.insert("_sips._tcp.example.com.", vec![srv(10, 0, 5061, "tls.example.com.")]);
+        dns.srv.insert("_sip._udp.example.com.", vec![
+            srv(20, 0, 5080, "backup.example.com."),
+            srv(10, 60, 5060, "a.example.com."),
+            srv(10, 40, 5060, "b.example.com."),
+        ]);
+        dns.srv.insert("_sip._udp.srv-only.net", vec![srv(10, 0, 5070, "sip.srv-only.net.")]);
+        for (host, ip) in [
+            ("tls.example.com.", "192.0.2.1"),
+            ("a.example.com.", "192.0.2.2"),
+            ("b.example.com.", "192.0.2.3"),
+            ("backup.example.com.", "192.0.2.4"),
+            ("sip.srv-only.net.", "192.0.2.5"),
+            ("plain.org", "192.0.2.6"),
+        ] {
+            dns.ip.insert(host, vec![ip.parse().unwrap()]);
+        }
+        dns
+    }
+
+    #[test]
+    fn test_order_srv() {
+        let records = vec![srv(20, 0, 1, "c"), srv(10, 60, 1, "a"), srv(10, 40, 1, "b")];
+        let targets = |records: Vec<Srv>, pick: u32| {
+            order_srv(records, |_| pick).into_iter().map(|r| r.target).collect::<Vec<_>>()
+        };
+        // sorted by weight, a draw of 0 takes the lightest first
+        assert_eq!(targets(records.clone(), 0), ["b", "a", "c"]);
+        assert_eq!(targets(records, 100), ["a", "b", "c"]);
+        assert_eq!(targets(vec![srv(10, 0, 1, "x"), srv(10, 0, 1, "y")], 0), ["x", "y"]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dns = stand_in();
+        let uri = |s: &str| rsip::Uri::try_from(s).unwrap();
+        let all = [Transport::Udp, Transport::Tcp, Transport::Tls];
+        let addrs = |targets: Vec<Target>| {
+            targets.into_iter().map(|t| (t.transport, t.addr.to_string())).collect::<Vec<_>>()
+        };
+
+        // NAPTR order puts TLS first, then UDP by priority
+        let targets = addrs(resolve(&dns, &uri("sip:example.com"), &all).await);
+        assert_eq!(targets[0], (Transport::Tls, "192.0.2.1:5061".to_owned()));
+        assert_eq!(targets.len(), 4);
+        assert_eq!(targets[3], (Transport::Udp, "192.0.2.4:5080".to_owned()));
+
+        // without a TLS listener only UDP is left
+        let targets = addrs(resolve(&dns, &uri("sip:example.com"), &[Transport::Udp]).await);
+        assert_eq!(targets.len(), 3);
+        assert!(targets.iter().all(|(t, _)| *t == Transport::Udp));
+
+        // no NAPTR, SRV of each transport
+        let targets = addrs(resolve(&dns, &uri("sip:srv-only.net"), &all).await);
+        assert_eq!(targets, [(Transport::Udp, "192.0.2.5:5070".to_owned())]);
+
+        // no SRV, the address on the default port
+        let targets = addrs(resolve(&dns, &uri("sip:plain.org"), &all).await);
+        assert_eq!(targets, [(Transport::Udp, "192.0.2.6:5060".to_owned())]);
+        let targets = addrs(resolve(&dns, &uri("sip:plain.org:5062;transport=tcp"), &all).await);
+        assert_eq!(targets, [(Transport::Tcp, "192.0.2.6:5062".to_owned())]);
+
+        let targets = addrs(resolve(&dns, &uri("sip:198.51.100.1"), &all).await);
+        assert_eq!(targets, [(Transport::Udp, "198.51.100.1:5060".to_owned())]);
+        assert!(resolve(&dns, &uri("sip:unknown.invalid"), &all).await.is_empty());
+
+        let targets = resolve(&dns, &uri("sip:example.com"), &all).await;
+        // TLS goes to the name, for the certificate to be checked against
+        assert_eq!(targets[0].destination().addr.to_string(), "tls.example.com:5061");
+        assert_eq!(targets[3].host, "backup.example.com");
+        assert_eq!(targets[3].destination().addr.to_string(), "192.0.2.4:5080");
+    }
+}
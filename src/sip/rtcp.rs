@@ -0,0 +1,365 @@
+use crate::sip::jitter::RtpStats;
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+const SR: u8 = 200;
+const RR: u8 = 201;
+const SDES: u8 = 202;
+const BYE: u8 = 203;
+const CNAME: u8 = 1;
+/// seconds from 1900 to 1970
+const NTP_EPOCH: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcpError {
+    Truncated,
+    Version(u8),
+}
+
+/// What one side reports about the stream it receives from `ssrc`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    /// lost share since the last report, in 1/256
+    pub fraction_lost: u8,
+    pub cumulative_lost: u32,
+    pub highest_seq: u32,
+    /// interarrival jitter in timestamp units
+    pub jitter: u32,
+    /// middle 32 bits of the NTP time of the last SR received
+    pub lsr: u32,
+    /// delay since that SR in 1/65536 s
+    pub dlsr: u32,
+}
+
+impl ReportBlock {
+    fn parse(b: &[u8]) -> Self {
+        let word = |i: usize| u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        ReportBlock {
+            ssrc: word(0),
+            fraction_lost: b[4],
+            cumulative_lost: word(4) & 0x00ff_ffff,
+            highest_seq: word(8),
+            jitter: word(12),
+            lsr: word(16),
+            dlsr: word(20),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend(self.ssrc.to_be_bytes());
+        out.extend(((self.fraction_lost as u32) << 24 | self.cumulative_lost.min(0x00ff_ffff)).to_be_bytes());
+        out.extend(self.highest_seq.to_be_bytes());
+        out.extend(self.jitter.to_be_bytes());
+        out.extend(self.lsr.to_be_bytes());
+        out.extend(self.dlsr.to_be_bytes());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtcpPacket {
+    SenderReport {
+        ssrc: u32,
+        ntp: u64,
+        packets: u32,
+        octets: u32,
+        reports: Vec<ReportBlock>,
+    },
+    ReceiverReport {
+        ssrc: u32,
+        reports: Vec<ReportBlock>,
+    },
+    Bye,
+    Other(u8),
+}
+
+fn word(b: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+fn reports(body: &[u8], count: usize) -> Vec<ReportBlock> {
+    body.chunks_exact(24).take(count).map(ReportBlock::parse).collect()
+}
+
+/// Split a compound RTCP packet.
+pub fn parse(mut buf: &[u8]) -> Result<Vec<RtcpPacket>, RtcpError> {
+    let mut packets = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            return Err(RtcpError::Truncated);
+        }
+        let version = buf[0] >> 6;
+        if version != 2 {
+            return Err(RtcpError::Version(version));
+        }
+        let count = (buf[0] & 0x1f) as usize;
+        let len = (u16::from_be_bytes([buf[2], buf[3]]) as usize + 1) * 4;
+        let pkt = buf.get(..len).ok_or(RtcpError::Truncated)?;
+        packets.push(match pkt[1] {
+            SR if len >= 28 + count * 24 => RtcpPacket::SenderReport {
+                ssrc: word(pkt, 4),
+                ntp: (word(pkt, 8) as u64) << 32 | word(pkt, 12) as u64,
+                packets: word(pkt, 20),
+                octets: word(pkt, 24),
+                reports: reports(&pkt[28..], count),
+            },
+            RR if len >= 8 + count * 24 => RtcpPacket::ReceiverReport {
+                ssrc: word(pkt, 4),
+                reports: reports(&pkt[8..], count),
+            },
+            SR | RR => return Err(RtcpError::Truncated),
+            BYE => RtcpPacket::Bye,
+            pt => RtcpPacket::Other(pt),
+        });
+        buf = &buf[len..];
+    }
+    Ok(packets)
+}
+
+fn header(out: &mut Vec<u8>, count: usize, pt: u8, words: usize) {
+    out.push(0x80 | count as u8);
+    out.push(pt);
+    out.extend((words as u16).to_be_bytes());
+}
+
+/// Current wall clock as a 64 bit NTP timestamp.
+pub fn ntp_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let frac = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (now.as_secs() + NTP_EPOCH) << 32 | frac
+}
+
+fn middle(ntp: u64) -> u32 {
+    (ntp >> 16) as u32
+}
+
+/// Call quality estimate (ITU-T G.107 E-model, simplified).
+pub fn mos(loss_percent: f64, jitter_ms: f64, rtt_ms: f64) -> f64 {
+    let latency = rtt_ms / 2.0 + jitter_ms * 2.0 + 10.0;
+    let r = if latency < 160.0 {
+        93.2 - latency / 40.0
+    } else {
+        93.2 - (latency - 120.0) / 10.0
+    };
+    let r = (r - loss_percent * 2.5).clamp(0.0, 100.0);
+    (1.0 + 0.035 * r + 0.000007 * r * (r - 60.0) * (100.0 - r)).clamp(1.0, 4.5)
+}
+
+/// Media counters shared by the RTP senders, the recorder and RTCP.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MediaStats {
+    pub sent_packets: u32,
+    pub sent_octets: u32,
+    pub last_ts: u32,
+    /// SSRC of the caller's stream
+    pub remote_ssrc: Option<u32>,
+    pub received: RtpStats,
+    /// the caller's last report on our stream
+    pub remote: Option<ReportBlock>,
+    pub rtt_ms: Option<f64>,
+}
+
+impl MediaStats {
+    pub fn mos(&self) -> f64 {
+        let expected = self.received.expected().max(1) as f64;
+        let loss = self.received.lost() as f64 * 100.0 / expected;
+        mos(loss, self.received.jitter_ms(), self.rtt_ms.unwrap_or_default())
+    }
+}
+
+pub type SharedStats = Arc<Mutex<MediaStats>>;
+
+/// Builds our reports and reads the caller's.
+#[derive(Debug)]
+pub struct Reporter {
+    ssrc: u32,
+    cname: String,
+    expected_prior: u64,
+    received_prior: u64,
+    /// middle bits of the caller's last SR and when it arrived
+    last_sr: Option<(u32, u64)>,
+}
+
+impl Reporter {
+    pub fn new(ssrc: u32, cname: String) -> Self {
+        Self {
+            ssrc,
+            cname,
+            expected_prior: 0,
+            received_prior: 0,
+            last_sr: None,
+        }
+    }
+
+    fn block(&mut self, stats: &MediaStats, ntp: u64) -> Option<ReportBlock> {
+        let ssrc = stats.remote_ssrc?;
+        let rx = &stats.received;
+        let expected = rx.expected();
+        let expected_interval = expected.saturating_sub(self.expected_prior);
+        let received_interval = rx.received.saturating_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = rx.received;
+        let lost_interval = expected_interval.saturating_sub(received_interval);
+        let fraction_lost = (lost_interval << 8).checked_div(expected_interval).unwrap_or(0);
+        let (lsr, dlsr) = self
+            .last_sr
+            .map(|(lsr, at)| (lsr, middle(ntp.saturating_sub(at))))
+            .unwrap_or_default();
+        Some(ReportBlock {
+            ssrc,
+            fraction_lost: fraction_lost.min(255) as u8,
+            cumulative_lost: rx.lost() as u32,
+            highest_seq: rx.highest(),
+            jitter: rx.jitter_units(),
+            lsr,
+            dlsr,
+        })
+    }
+
+    /// SR while we are sending, RR otherwise, followed by our CNAME.
+    pub fn report(&mut self, stats: &MediaStats, ntp: u64) -> Vec<u8> {
+        let blocks = self.block(stats, ntp).into_iter().collect::<Vec<_>>();
+        let mut out = vec![];
+        if stats.sent_packets > 0 {
+            header(&mut out, blocks.len(), SR, 6 + blocks.len() * 6);
+            out.extend(self.ssrc.to_be_bytes());
+            out.extend(ntp.to_be_bytes());
+            out.extend(stats.last_ts.to_be_bytes());
+            out.extend(stats.sent_packets.to_be_bytes());
+            out.extend(stats.sent_octets.to_be_bytes());
+        } else {
+            header(&mut out, blocks.len(), RR, 1 + blocks.len() * 6);
+            out.extend(self.ssrc.to_be_bytes());
+        }
+        blocks.iter().for_each(|b| b.write(&mut out));
+        self.sdes(&mut out);
+        out
+    }
+
+    /// Final report and BYE when the call ends.
+    pub fn bye(&mut self, stats: &MediaStats, ntp: u64) -> Vec<u8> {
+        let mut out = self.report(stats, ntp);
+        header(&mut out, 1, BYE, 1);
+        out.extend(self.ssrc.to_be_bytes());
+        out
+    }
+
+    fn sdes(&self, out: &mut Vec<u8>) {
+        let cname = &self.cname.as_bytes()[..self.cname.len().min(255)];
+        // ssrc, type, length, text and the terminating null, padded to a word
+        let words = (4 + 2 + cname.len() + 1).div_ceil(4);
+        header(out, 1, SDES, words);
+        let start = out.len();
+        out.extend(self.ssrc.to_be_bytes());
+        out.push(CNAME);
+        out.push(cname.len() as u8);
+        out.extend(cname);
+        out.resize(start + words * 4, 0);
+    }
+
+    /// Read the caller's reports, returns false on BYE.
+    pub fn receive(&mut self, buf: &[u8], stats: &mut MediaStats, ntp: u64) -> Result<bool, RtcpError> {
+        for pkt in parse(buf)? {
+            let blocks = match pkt {
+                RtcpPacket::SenderReport { ssrc, ntp: sr, packets, octets, reports } => {
+                    debug!("RTCP SR from {ssrc:08x}: {packets} packets {octets} bytes");
+                    self.last_sr = Some((middle(sr), ntp));
+                    reports
+                }
+                RtcpPacket::ReceiverReport { ssrc, reports } => {
+                    debug!("RTCP RR from {ssrc:08x}");
+                    reports
+                }
+                RtcpPacket::Bye => return Ok(false),
+                RtcpPacket::Other(_) => continue,
+            };
+            for b in blocks.into_iter().filter(|b| b.ssrc == self.ssrc) {
+                if b.lsr != 0 {
+                    let rtt = middle(ntp).wrapping_sub(b.lsr).wrapping_sub(b.dlsr);
+                    // ignore reports against a clock far off ours
+                    if rtt < 10 << 16 {
+                        stats.rtt_ms = Some(rtt as f64 * 1000.0 / 65536.0);
+                    }
+                }
+                stats.remote = Some(b);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SR from a softphone with one report block on SSRC 0x01020304, then BYE
+    const SENDER_REPORT: &[u8] = &[
+        0x81, 0xc8, 0x00, 0x0c, 0x12, 0x34, 0x56, 0x78,
+        0xe9, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x3e, 0x80, 0x00, 0x00, 0x00, 0x64,
+        0x00, 0x00, 0x3e, 0x80,
+        0x01, 0x02, 0x03, 0x04, 0x40, 0x00, 0x00, 0x05,
+        0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x10,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x81, 0xcb, 0x00, 0x01, 0x12, 0x34, 0x56, 0x78,
+    ];
+
+    #[test]
+    fn test_parse() {
+        let packets = parse(SENDER_REPORT).unwrap();
+        assert_eq!(packets.len(), 2);
+        let RtcpPacket::SenderReport { ssrc, packets: n, reports, .. } = &packets[0] else {
+            panic!("not a SR: {:?}", packets[0]);
+        };
+        assert_eq!((*ssrc, *n), (0x12345678, 100));
+        assert_eq!(reports[0].fraction_lost, 64);
+        assert_eq!(reports[0].cumulative_lost, 5);
+        assert_eq!(reports[0].highest_seq, 100);
+        assert_eq!(packets[1], RtcpPacket::Bye);
+        assert_eq!(parse(&SENDER_REPORT[..20]), Err(RtcpError::Truncated));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut ours = Reporter::new(0x01020304, "voicemail@example.com".into());
+        let mut stats = MediaStats {
+            sent_packets: 50,
+            sent_octets: 8000,
+            remote_ssrc: Some(0x12345678),
+            ..Default::default()
+        };
+        let ntp = 0xe900_1234_5678_0000;
+        let report = ours.report(&stats, ntp);
+        assert_eq!(report.len() % 4, 0);
+        let packets = parse(&report).unwrap();
+        assert!(matches!(
+            &packets[0],
+            RtcpPacket::SenderReport { ssrc: 0x01020304, packets: 50, octets: 8000, reports, .. }
+                if reports[0].ssrc == 0x12345678
+        ));
+        assert_eq!(packets[1], RtcpPacket::Other(SDES));
+
+        // the caller answers 100 ms later, having held our SR for 20 ms
+        let mut theirs = Reporter::new(0x12345678, "caller".into());
+        let mut remote = MediaStats { remote_ssrc: Some(0x01020304), ..Default::default() };
+        theirs.receive(&report, &mut remote, ntp + (80 << 32) / 1000).unwrap();
+        let rr = theirs.report(&remote, ntp + (100 << 32) / 1000);
+        assert!(ours.receive(&rr, &mut stats, ntp + (180 << 32) / 1000).unwrap());
+        let rtt = stats.rtt_ms.unwrap();
+        assert!((rtt - 160.0).abs() < 1.0, "{rtt}");
+        let bye = ours.bye(&stats, ntp);
+        assert!(!ours.receive(&bye, &mut stats, ntp).unwrap());
+    }
+
+    #[test]
+    fn test_mos() {
+        let good = mos(0.0, 5.0, 40.0);
+        let lossy = mos(5.0, 30.0, 200.0);
+        assert!(good > 4.2 && good <= 4.5, "{good}");
+        assert!(lossy < good && lossy > 1.0, "{lossy}");
+        assert_eq!(mos(100.0, 0.0, 0.0), 1.0);
+    }
+}
@@ -0,0 +1,179 @@
+use tracing::debug;
+
+/// fixed part of the RTP header
+const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtpError {
+    /// shorter than the header it announces
+    Truncated,
+    Version(u8),
+    /// padding longer than the payload
+    Padding,
+}
+
+/// An RTP packet borrowed from the receive buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpPacket<'a> {
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    /// Parse a packet, skipping the CSRC list, header extension and padding.
+    pub fn parse(buf: &'a [u8]) -> Result<Self, RtpError> {
+        if buf.len() < HEADER_LEN {
+            return Err(RtpError::Truncated);
+        }
+        let version = buf[0] >> 6;
+        if version != 2 {
+            return Err(RtpError::Version(version));
+        }
+        let padding = buf[0] & 0x20 != 0;
+        let extension = buf[0] & 0x10 != 0;
+        let csrc_count = (buf[0] & 0x0f) as usize;
+
+        let mut start = HEADER_LEN + csrc_count * 4;
+        if extension {
+            let ext = buf.get(start..start + 4).ok_or(RtpError::Truncated)?;
+            let words = u16::from_be_bytes([ext[2], ext[3]]) as usize;
+            start += 4 + words * 4;
+        }
+        if start > buf.len() {
+            return Err(RtpError::Truncated);
+        }
+
+        let mut end = buf.len();
+        if padding {
+            let pad = buf[end - 1] as usize;
+            if pad == 0 || start + pad > end {
+                return Err(RtpError::Padding);
+            }
+            end -= pad;
+        }
+
+        Ok(RtpPacket {
+            payload_type: buf[1] & 0x7f,
+            sequence: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            ssrc: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            payload: &buf[start..end],
+        })
+    }
+}
+
+/// Validates incoming packets and latches onto the first SSRC seen.
+#[derive(Debug, Default)]
+pub struct RtpReceiver {
+    ssrc: Option<u32>,
+    /// packets that could not be parsed
+    pub malformed: u64,
+    /// packets from another source than the latched one
+    pub foreign: u64,
+}
+
+impl RtpReceiver {
+    pub fn accept<'a>(&mut self, buf: &'a [u8]) -> Option<RtpPacket<'a>> {
+        let rtp = match RtpPacket::parse(buf) {
+            Ok(rtp) => rtp,
+            Err(e) => {
+                debug!("malformed RTP packet: {:?}", e);
+                self.malformed += 1;
+                return None;
+            }
+        };
+        match self.ssrc {
+            Some(ssrc) if ssrc != rtp.ssrc => {
+                self.foreign += 1;
+                None
+            }
+            _ => {
+                self.ssrc = Some(rtp.ssrc);
+                Some(rtp)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PCMU packet from a softphone, payload cut to 4 bytes
+    const PCMU: &[u8] = &[
+        0x80, 0x00, 0x1f, 0x40, 0x00, 0x00, 0x3e, 0x80, 0x12, 0x34, 0x56, 0x78,
+        0xff, 0xfe, 0x7f, 0x7e,
+    ];
+    /// PCMA packet mixed by a conference bridge, two CSRCs
+    const CSRC: &[u8] = &[
+        0x82, 0x88, 0x00, 0x01, 0x00, 0x00, 0x00, 0xa0, 0x12, 0x34, 0x56, 0x78,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+        0xd5, 0xd5, 0xd5, 0xd5,
+    ];
+    /// one-byte header extension carrying an audio level (RFC 6464)
+    const EXTENSION: &[u8] = &[
+        0x90, 0x00, 0x00, 0x02, 0x00, 0x00, 0x01, 0x40, 0x12, 0x34, 0x56, 0x78,
+        0xbe, 0xde, 0x00, 0x01, 0x10, 0x7f, 0x00, 0x00,
+        0xff, 0xff, 0xff, 0xff,
+    ];
+    /// padded to a multiple of 4 as SRTP senders do
+    const PADDING: &[u8] = &[
+        0xa0, 0x00, 0x00, 0x03, 0x00, 0x00, 0x01, 0xe0, 0x12, 0x34, 0x56, 0x78,
+        0xff, 0xff, 0x00, 0x00, 0x00, 0x04,
+    ];
+    /// telephone-event '5' end packet
+    const EVENT: &[u8] = &[
+        0x80, 0x65, 0x00, 0x04, 0x00, 0x00, 0x02, 0x80, 0x12, 0x34, 0x56, 0x78,
+        0x05, 0x8a, 0x03, 0x20,
+    ];
+
+    #[test]
+    fn test_parse() {
+        let rtp = RtpPacket::parse(PCMU).unwrap();
+        assert_eq!((rtp.payload_type, rtp.sequence, rtp.timestamp), (0, 8000, 16000));
+        assert_eq!(rtp.ssrc, 0x12345678);
+        assert_eq!(rtp.payload, &[0xff, 0xfe, 0x7f, 0x7e]);
+
+        let rtp = RtpPacket::parse(CSRC).unwrap();
+        assert_eq!(rtp.payload_type, 8);
+        assert_eq!(rtp.payload, &[0xd5; 4]);
+
+        assert_eq!(RtpPacket::parse(EXTENSION).unwrap().payload, &[0xff; 4]);
+        assert_eq!(RtpPacket::parse(PADDING).unwrap().payload, &[0xff, 0xff]);
+
+        let rtp = RtpPacket::parse(EVENT).unwrap();
+        assert_eq!(rtp.payload_type, 101);
+        assert_eq!(rtp.payload, &[0x05, 0x8a, 0x03, 0x20]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(RtpPacket::parse(&PCMU[..8]), Err(RtpError::Truncated));
+        assert_eq!(RtpPacket::parse(&CSRC[..16]), Err(RtpError::Truncated));
+        assert_eq!(RtpPacket::parse(&EXTENSION[..18]), Err(RtpError::Truncated));
+
+        let mut stun = PCMU.to_vec();
+        stun[0] = 0x00;
+        assert_eq!(RtpPacket::parse(&stun), Err(RtpError::Version(0)));
+
+        let mut padding = PADDING.to_vec();
+        *padding.last_mut().unwrap() = 0x20;
+        assert_eq!(RtpPacket::parse(&padding), Err(RtpError::Padding));
+    }
+
+    #[test]
+    fn test_ssrc_latch() {
+        let mut rx = RtpReceiver::default();
+        assert!(rx.accept(PCMU).is_some());
+        assert!(rx.accept(&PCMU[..4]).is_none());
+
+        let mut other = PCMU.to_vec();
+        other[11] = 0x79;
+        assert!(rx.accept(&other).is_none());
+        assert!(rx.accept(EVENT).is_some());
+        assert_eq!((rx.malformed, rx.foreign), (1, 1));
+    }
+}
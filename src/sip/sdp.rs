@@ -0,0 +1,520 @@
+use crate::sip::codec::Codec;
+use crate::sip::srtp::{MasterKey, SUITE, SrtpKeys, SrtpPolicy};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+const DEFAULT_PTIME: u32 = 20;
+/// payload type of telephone-event in our offers
+const DTMF_PT: u8 = 101;
+
+#[derive(Debug)]
+pub enum SdpError {
+    Parse(String),
+    NotAcceptable,
+}
+
+impl fmt::Display for SdpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SdpError::Parse(e) => write!(f, "invalid SDP: {e}"),
+            SdpError::NotAcceptable => write!(f, "no acceptable media in SDP offer"),
+        }
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+/// Media direction attribute, seen from the side that wrote it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    /// direction of the answer to an offer with this direction
+    pub fn reverse(&self) -> Self {
+        match self {
+            Direction::SendOnly => Direction::RecvOnly,
+            Direction::RecvOnly => Direction::SendOnly,
+            d => *d,
+        }
+    }
+
+    pub fn sends(&self) -> bool {
+        matches!(self, Direction::SendRecv | Direction::SendOnly)
+    }
+
+    pub fn receives(&self) -> bool {
+        matches!(self, Direction::SendRecv | Direction::RecvOnly)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpMap {
+    pub encoding: String,
+    pub clock_rate: u32,
+}
+
+/// `a=crypto` line of SDES (RFC 4568).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crypto {
+    pub tag: u32,
+    pub suite: String,
+    pub key_params: String,
+}
+
+/// One `m=` section of an offer.
+#[derive(Debug, Clone)]
+pub struct MediaOffer {
+    pub media: String,
+    pub port: u16,
+    pub proto: String,
+    /// format tokens as offered, names for streams other than RTP
+    pub fmt_list: Vec<String>,
+    pub formats: Vec<u8>,
+    pub rtpmap: HashMap<u8, RtpMap>,
+    pub fmtp: HashMap<u8, String>,
+    pub ptime: Option<u32>,
+    pub direction: Option<Direction>,
+    pub connection: Option<IpAddr>,
+    pub crypto: Vec<Crypto>,
+}
+
+impl MediaOffer {
+    /// `a=rtpmap` if present, otherwise the static payload type mapping
+    pub fn encoding(&self, pt: u8) -> Option<RtpMap> {
+        self.rtpmap.get(&pt).cloned().or_else(|| {
+            Codec::from_payload_type(pt).map(|c| RtpMap {
+                encoding: c.name().to_string(),
+                clock_rate: 8000,
+            })
+        })
+    }
+
+    fn is_rtp_audio(&self) -> bool {
+        self.media == "audio" && self.port != 0 && self.proto.starts_with("RTP/")
+    }
+
+    fn is_secure(&self) -> bool {
+        self.proto.contains("SAVP")
+    }
+
+    /// Keys for the first usable `a=crypto`, with a new key of ours.
+    fn srtp(&self) -> Option<SrtpKeys> {
+        self.crypto.iter().filter(|c| c.suite == SUITE).find_map(|c| {
+            Some(SrtpKeys {
+                tag: c.tag,
+                local: MasterKey::random(),
+                remote: MasterKey::parse(&c.key_params)?,
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionOffer {
+    pub connection: Option<IpAddr>,
+    pub direction: Option<Direction>,
+    pub media: Vec<MediaOffer>,
+}
+
+impl SessionOffer {
+    pub fn parse(body: &str) -> Result<Self, SdpError> {
+        let mut offer = SessionOffer {
+            connection: None,
+            direction: None,
+            media: vec![],
+        };
+        for line in body.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| SdpError::Parse(line.to_string()))?;
+            match key {
+                "c" => {
+                    let addr = parse_connection(value)?;
+                    match offer.media.last_mut() {
+                        Some(m) => m.connection = Some(addr),
+                        None => offer.connection = Some(addr),
+                    }
+                }
+                "m" => offer.media.push(parse_media(value)?),
+                "a" => {
+                    let (name, arg) = match value.split_once(':') {
+                        Some((n, a)) => (n, Some(a.trim())),
+                        None => (value, None),
+                    };
+                    if let Some(d) = Direction::parse(name) {
+                        match offer.media.last_mut() {
+                            Some(m) => m.direction = Some(d),
+                            None => offer.direction = Some(d),
+                        }
+                        continue;
+                    }
+                    let (Some(m), Some(arg)) = (offer.media.last_mut(), arg) else {
+                        continue;
+                    };
+                    match name {
+                        "rtpmap" => {
+                            if let Some((pt, map)) = parse_rtpmap(arg) {
+                                m.rtpmap.insert(pt, map);
+                            }
+                        }
+                        "fmtp" => {
+                            if let Some((pt, params)) = arg.split_once(' ')
+                                && let Ok(pt) = pt.parse::<u8>()
+                            {
+                                m.fmtp.insert(pt, params.trim().to_string());
+                            }
+                        }
+                        "ptime" => m.ptime = arg.parse::<u32>().ok(),
+                        "crypto" => m.crypto.extend(parse_crypto(arg)),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        if offer.media.is_empty() {
+            return Err(SdpError::Parse("no media description".to_string()));
+        }
+        Ok(offer)
+    }
+}
+
+fn parse_connection(value: &str) -> Result<IpAddr, SdpError> {
+    // IN IP4 192.0.2.1[/ttl]
+    let mut it = value.split_whitespace();
+    let addr = it
+        .nth(2)
+        .and_then(|a| a.split('/').next())
+        .ok_or_else(|| SdpError::Parse(format!("c={value}")))?;
+    addr.parse::<IpAddr>()
+        .map_err(|e| SdpError::Parse(format!("c={value}: {e}")))
+}
+
+fn parse_media(value: &str) -> Result<MediaOffer, SdpError> {
+    // audio 4000[/2] RTP/AVP 8 0 101
+    let mut it = value.split_whitespace();
+    let err = || SdpError::Parse(format!("m={value}"));
+    let media = it.next().ok_or_else(err)?.to_string();
+    let port = it
+        .next()
+        .and_then(|p| p.split('/').next())
+        .and_then(|p| p.parse::<u16>().ok())
+        .ok_or_else(err)?;
+    let proto = it.next().ok_or_else(err)?.to_string();
+    let fmt_list = it.map(str::to_owned).collect::<Vec<_>>();
+    let formats = fmt_list.iter().filter_map(|f| f.parse::<u8>().ok()).collect();
+    Ok(MediaOffer {
+        media,
+        port,
+        proto,
+        fmt_list,
+        formats,
+        rtpmap: HashMap::new(),
+        fmtp: HashMap::new(),
+        ptime: None,
+        direction: None,
+        connection: None,
+        crypto: vec![],
+    })
+}
+
+fn parse_crypto(arg: &str) -> Option<Crypto> {
+    // 1 AES_CM_128_HMAC_SHA1_80 inline:<key>[|2^20] [session params]
+    let mut it = arg.split_whitespace();
+    Some(Crypto {
+        tag: it.next()?.parse().ok()?,
+        suite: it.next()?.to_string(),
+        key_params: it.next()?.to_string(),
+    })
+}
+
+fn parse_rtpmap(arg: &str) -> Option<(u8, RtpMap)> {
+    // 8 PCMA/8000[/1]
+    let (pt, enc) = arg.split_once(' ')?;
+    let mut it = enc.trim().split('/');
+    let encoding = it.next()?.to_string();
+    let clock_rate = it.next()?.parse::<u32>().ok()?;
+    Some((pt.parse::<u8>().ok()?, RtpMap { encoding, clock_rate }))
+}
+
+/// Result of matching an offer against our codecs.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub codec: Codec,
+    pub peer: SocketAddr,
+    pub ptime: u32,
+    /// payload type of telephone-event, if offered
+    pub dtmf: Option<u8>,
+    /// direction of our answer
+    pub direction: Direction,
+    /// SDES keys when the media is encrypted
+    pub srtp: Option<SrtpKeys>,
+    media_index: usize,
+}
+
+impl Negotiated {
+    /// samples per packet at 8 kHz
+    pub fn samples(&self) -> usize {
+        8 * self.ptime as usize
+    }
+
+    /// whether `pt` is one of the payload types we agreed on
+    pub fn accepts(&self, pt: u8) -> bool {
+        pt == self.codec.payload_type() || self.dtmf == Some(pt)
+    }
+
+    pub fn answer(&self, offer: &SessionOffer, local: SocketAddr, ssrc: u32) -> String {
+        let mut sdp = session(local.ip());
+        for (i, m) in offer.media.iter().enumerate() {
+            if i != self.media_index {
+                // rejected stream, its formats echoed as at least one is required
+                let fmts = m.fmt_list.join(" ");
+                sdp.push_str(&format!("m={} 0 {} {fmts}\r\n", m.media, m.proto));
+                continue;
+            }
+            let pt = self.codec.payload_type();
+            let dtmf = self.dtmf.map(|d| format!(" {d}")).unwrap_or_default();
+            sdp.push_str(&format!(
+                "m=audio {} {} {pt}{dtmf}\r\n\
+                a=rtpmap:{pt} {}/8000\r\n",
+                local.port(),
+                m.proto,
+                self.codec.name(),
+            ));
+            if let Some(k) = &self.srtp {
+                sdp.push_str(&format!("a=crypto:{} {SUITE} {}\r\n", k.tag, k.local.inline()));
+            }
+            if let Some(d) = self.dtmf {
+                sdp.push_str(&format!(
+                    "a=rtpmap:{d} telephone-event/8000\r\n\
+                    a=fmtp:{d} 0-15\r\n"
+                ));
+            }
+            sdp.push_str(&format!(
+                "a=ptime:{}\r\n\
+                a=ssrc:{ssrc}\r\n\
+                a={}\r\n",
+                self.ptime,
+                self.direction.as_str(),
+            ));
+        }
+        sdp
+    }
+}
+
+fn session(ip: IpAddr) -> String {
+    let ipv = if ip.is_ipv6() { "IP6" } else { "IP4" };
+    format!(
+        "v=0\r\n\
+        o=- 0 0 IN {ipv} {ip}\r\n\
+        s=rsipstack example\r\n\
+        c=IN {ipv} {ip}\r\n\
+        t=0 0\r\n"
+    )
+}
+
+/// Offer of an outgoing call, `codecs` in our preference order, RTP/SAVP
+/// when `key` is given.
+pub fn offer(local: SocketAddr, codecs: &[Codec], ssrc: u32, key: Option<&MasterKey>) -> String {
+    let mut sdp = session(local.ip());
+    let pts = codecs.iter().map(|c| c.payload_type().to_string()).collect::<Vec<_>>();
+    let proto = if key.is_some() { "RTP/SAVP" } else { "RTP/AVP" };
+    sdp.push_str(&format!("m=audio {} {proto} {} {DTMF_PT}\r\n", local.port(), pts.join(" ")));
+    for c in codecs {
+        sdp.push_str(&format!("a=rtpmap:{} {}/8000\r\n", c.payload_type(), c.name()));
+    }
+    if let Some(key) = key {
+        sdp.push_str(&format!("a=crypto:1 {SUITE} {}\r\n", key.inline()));
+    }
+    sdp.push_str(&format!(
+        "a=rtpmap:{DTMF_PT} telephone-event/8000\r\n\
+        a=fmtp:{DTMF_PT} 0-15\r\n\
+        a=ptime:{DEFAULT_PTIME}\r\n\
+        a=ssrc:{ssrc}\r\n\
+        a=sendrecv\r\n"
+    ));
+    sdp
+}
+
+/// Pick the first of `supported` (in our preference order) the offer
+/// contains, in a stream `policy` allows.
+pub fn negotiate(offer: &SessionOffer, supported: &[Codec], policy: SrtpPolicy) -> Result<Negotiated, SdpError> {
+    for (i, m) in offer.media.iter().enumerate() {
+        if !m.is_rtp_audio() {
+            continue;
+        }
+        let srtp = match policy {
+            SrtpPolicy::Disabled => None,
+            _ => m.srtp(),
+        };
+        if srtp.is_none() && (m.is_secure() || policy == SrtpPolicy::Mandatory) {
+            continue;
+        }
+        let Some(addr) = m.connection.or(offer.connection) else {
+            continue;
+        };
+        let codec = supported.iter().copied().find(|c| {
+            m.formats.iter().any(|pt| {
+                *pt == c.payload_type()
+                    && m.encoding(*pt).is_some_and(|r| {
+                        r.encoding.eq_ignore_ascii_case(c.name()) && r.clock_rate == 8000
+                    })
+            })
+        });
+        let Some(codec) = codec else {
+            continue;
+        };
+        let dtmf = m.formats.iter().copied().find(|pt| {
+            m.encoding(*pt).is_some_and(|r| {
+                r.encoding.eq_ignore_ascii_case("telephone-event") && r.clock_rate == 8000
+            })
+        });
+        let ptime = match m.ptime {
+            Some(p) if (10..=60).contains(&p) && p % 10 == 0 => p,
+            _ => DEFAULT_PTIME,
+        };
+        let direction = m
+            .direction
+            .or(offer.direction)
+            .unwrap_or(Direction::SendRecv)
+            .reverse();
+        return Ok(Negotiated {
+            codec,
+            peer: SocketAddr::new(addr, m.port),
+            ptime,
+            dtmf,
+            direction,
+            srtp,
+            media_index: i,
+        });
+    }
+    Err(SdpError::NotAcceptable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 1 1 IN IP4 192.0.2.1\r\n\
+        s=-\r\n\
+        c=IN IP4 192.0.2.1\r\n\
+        t=0 0\r\n\
+        m=video 5000 RTP/AVP 96\r\n\
+        m=audio 4000 RTP/AVP 8 0 101\r\n\
+        c=IN IP4 198.51.100.7\r\n\
+        a=rtpmap:8 PCMA/8000\r\n\
+        a=rtpmap:0 PCMU/8000\r\n\
+        a=rtpmap:101 telephone-event/8000\r\n\
+        a=fmtp:101 0-15\r\n\
+        a=ptime:30\r\n\
+        a=sendonly\r\n";
+
+    #[test]
+    fn test_negotiate() {
+        let offer = SessionOffer::parse(OFFER).unwrap();
+        assert_eq!(offer.media[1].formats, vec![8, 0, 101]);
+        assert_eq!(offer.media[1].fmtp.get(&101).unwrap(), "0-15");
+
+        let n = negotiate(&offer, &[Codec::Pcmu, Codec::Pcma], SrtpPolicy::Optional).unwrap();
+        assert_eq!(n.codec, Codec::Pcmu);
+        assert_eq!(n.peer, "198.51.100.7:4000".parse().unwrap());
+        assert_eq!(n.ptime, 30);
+        assert_eq!(n.direction, Direction::RecvOnly);
+        assert_eq!(n.dtmf, Some(101));
+
+        let n = negotiate(&offer, &[Codec::Pcma], SrtpPolicy::Optional).unwrap();
+        let answer = n.answer(&offer, "10.0.0.1:5062".parse().unwrap(), 1);
+        assert!(answer.contains("m=video 0 RTP/AVP 96\r\n"));
+        assert!(answer.contains("m=audio 5062 RTP/AVP 8 101\r\n"));
+        assert!(answer.contains("a=rtpmap:101 telephone-event/8000\r\n"));
+        assert!(answer.contains("a=recvonly\r\n"));
+
+        // streams other than RTP are rejected with their formats
+        let offer = SessionOffer::parse(&format!("{OFFER}m=application 9 UDP/BFCP *\r\nm=image 0 udptl t38\r\n")).unwrap();
+        let answer = n.answer(&offer, "10.0.0.1:5062".parse().unwrap(), 1);
+        assert!(answer.contains("m=application 0 UDP/BFCP *\r\n"));
+        assert!(answer.contains("m=image 0 udptl t38\r\n"));
+    }
+
+    #[test]
+    fn test_offer() {
+        let sdp = offer("10.0.0.1:5062".parse().unwrap(), &[Codec::Pcma, Codec::Pcmu], 7, None);
+        assert!(sdp.contains("m=audio 5062 RTP/AVP 8 0 101\r\n"));
+        // the answer to our offer is read the same way
+        let n = negotiate(&SessionOffer::parse(&sdp).unwrap(), &[Codec::Pcmu, Codec::Pcma], SrtpPolicy::Optional).unwrap();
+        assert_eq!(n.codec, Codec::Pcmu);
+        assert_eq!(n.peer, "10.0.0.1:5062".parse().unwrap());
+        assert_eq!(n.dtmf, Some(101));
+        assert_eq!(n.direction, Direction::SendRecv);
+    }
+
+    #[test]
+    fn test_not_acceptable() {
+        let offer = SessionOffer::parse(
+            "v=0\r\nc=IN IP4 192.0.2.1\r\nm=audio 4000 RTP/AVP 9 18\r\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            negotiate(&offer, &[Codec::Pcmu, Codec::Pcma], SrtpPolicy::Optional),
+            Err(SdpError::NotAcceptable)
+        ));
+    }
+
+    #[test]
+    fn test_srtp() {
+        let key = "inline:WVNfX19zZW1jdGwgKCkgewkyMjA7fQp9CnVubGVz|2^20";
+        let secure = OFFER.replace("RTP/AVP 8", "RTP/SAVP 8")
+            + &format!("a=crypto:1 AES_CM_256_HMAC_SHA1_80 {key}\r\na=crypto:2 {SUITE} {key}\r\n");
+        let received = SessionOffer::parse(&secure).unwrap();
+        let codecs = [Codec::Pcmu];
+        for policy in [SrtpPolicy::Mandatory, SrtpPolicy::Optional] {
+            let n = negotiate(&received, &codecs, policy).unwrap();
+            let keys = n.srtp.unwrap();
+            assert_eq!(keys.tag, 2);
+            assert_eq!(keys.remote, MasterKey::parse(key).unwrap());
+            let answer = n.answer(&received, "10.0.0.1:5062".parse().unwrap(), 1);
+            assert!(answer.contains("m=audio 5062 RTP/SAVP 0 101\r\n"));
+            assert!(answer.contains(&format!("a=crypto:2 {SUITE} {}\r\n", keys.local.inline())));
+        }
+        assert!(matches!(negotiate(&received, &codecs, SrtpPolicy::Disabled), Err(SdpError::NotAcceptable)));
+
+        // plain offers are refused only when SRTP is mandatory
+        let plain = SessionOffer::parse(OFFER).unwrap();
+        assert!(negotiate(&plain, &codecs, SrtpPolicy::Optional).unwrap().srtp.is_none());
+        assert!(matches!(negotiate(&plain, &codecs, SrtpPolicy::Mandatory), Err(SdpError::NotAcceptable)));
+
+        let ours = MasterKey::random();
+        let sdp = offer("10.0.0.1:5062".parse().unwrap(), &codecs, 7, Some(&ours));
+        assert!(sdp.contains("m=audio 5062 RTP/SAVP 0 101\r\n"));
+        let n = negotiate(&SessionOffer::parse(&sdp).unwrap(), &codecs, SrtpPolicy::Mandatory).unwrap();
+        assert_eq!(n.srtp.unwrap().remote, ours);
+    }
+}
@@ -0,0 +1,445 @@
+use aes::Aes128;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::ValueEnum;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type HmacSha1 = Hmac<Sha1>;
+
+/// the only crypto suite offered and accepted in `a=crypto`
+pub const SUITE: &str = "AES_CM_128_HMAC_SHA1_80";
+const KEY_LEN: usize = 16;
+const SALT_LEN: usize = 14;
+const AUTH_KEY_LEN: usize = 20;
+const TAG_LEN: usize = 10;
+const RTP_HEADER: usize = 12;
+const RTCP_HEADER: usize = 8;
+/// set in the SRTCP index when the payload is encrypted
+const E_FLAG: u32 = 0x8000_0000;
+
+/// Whether calls must, may or must not use SRTP.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrtpPolicy {
+    /// reject offers without a usable `a=crypto` with 488, offer RTP/SAVP
+    Mandatory,
+    /// encrypt when the offer asks for it, offer plain RTP
+    Optional,
+    /// never encrypt, RTP/SAVP offers are rejected
+    Disabled,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SrtpError {
+    Truncated,
+    Auth,
+    Replay,
+}
+
+impl fmt::Display for SrtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SrtpError::Truncated => write!(f, "truncated packet"),
+            SrtpError::Auth => write!(f, "authentication failed"),
+            SrtpError::Replay => write!(f, "replayed packet"),
+        }
+    }
+}
+
+impl std::error::Error for SrtpError {}
+
+/// Master key and salt of one direction, sent inline in `a=crypto`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MasterKey([u8; KEY_LEN + SALT_LEN]);
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// Key parameters of `a=crypto`: `inline:<base64>[|lifetime]`. Keys
+    /// with an MKI are not supported.
+    pub fn parse(params: &str) -> Option<Self> {
+        let mut parts = params.strip_prefix("inline:")?.split('|');
+        let key = STANDARD.decode(parts.next()?).ok()?;
+        if parts.any(|p| p.contains(':')) {
+            return None;
+        }
+        key.try_into().ok().map(Self)
+    }
+
+    pub fn inline(&self) -> String {
+        format!("inline:{}", STANDARD.encode(self.0))
+    }
+
+    /// Session key `label` of RFC 3711 4.3.1, key derivation rate 0.
+    fn derive(&self, label: u8, out: &mut [u8]) {
+        let (key, salt) = self.0.split_at(KEY_LEN);
+        let mut iv = [0; 16];
+        iv[..SALT_LEN].copy_from_slice(salt);
+        iv[7] ^= label;
+        out.fill(0);
+        Aes128Ctr::new(key.into(), &iv.into()).apply_keystream(out);
+    }
+}
+
+/// Keys agreed on in `a=crypto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SrtpKeys {
+    pub tag: u32,
+    /// what we encrypt with, sent in our SDP
+    pub local: MasterKey,
+    pub remote: MasterKey,
+}
+
+struct SessionKeys {
+    cipher: [u8; KEY_LEN],
+    auth: [u8; AUTH_KEY_LEN],
+    salt: [u8; SALT_LEN],
+}
+
+impl SessionKeys {
+    /// labels 0-2 for SRTP, 3-5 for SRTCP
+    fn new(master: &MasterKey, first_label: u8) -> Self {
+        let mut keys = Self {
+            cipher: [0; KEY_LEN],
+            auth: [0; AUTH_KEY_LEN],
+            salt: [0; SALT_LEN],
+        };
+        master.derive(first_label, &mut keys.cipher);
+        master.derive(first_label + 1, &mut keys.auth);
+        master.derive(first_label + 2, &mut keys.salt);
+        keys
+    }
+
+    fn crypt(&self, ssrc: u32, index: u64, data: &mut [u8]) {
+        let mut iv = [0; 16];
+        iv[..SALT_LEN].copy_from_slice(&self.salt);
+        for (b, s) in iv[4..8].iter_mut().zip(ssrc.to_be_bytes()) {
+            *b ^= s;
+        }
+        for (b, i) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+            *b ^= i;
+        }
+        Aes128Ctr::new(&self.cipher.into(), &iv.into()).apply_keystream(data);
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha1 {
+        let mut mac = HmacSha1::new_from_slice(&self.auth).expect("HMAC key");
+        for p in parts {
+            mac.update(p);
+        }
+        mac
+    }
+
+    fn tag(&self, parts: &[&[u8]]) -> Vec<u8> {
+        self.mac(parts).finalize().into_bytes()[..TAG_LEN].to_vec()
+    }
+
+    fn verify(&self, parts: &[&[u8]], tag: &[u8]) -> Result<(), SrtpError> {
+        self.mac(parts).verify_truncated_left(tag).map_err(|_| SrtpError::Auth)
+    }
+}
+
+/// Sliding window of the last 64 packet indexes received.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, index: u64) -> Result<(), SrtpError> {
+        match self.highest {
+            Some(h) if index <= h => {
+                let age = h - index;
+                if age >= 64 || self.seen & (1 << age) != 0 {
+                    return Err(SrtpError::Replay);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn mark(&mut self, index: u64) {
+        match self.highest {
+            Some(h) if index <= h => self.seen |= 1 << (h - index),
+            Some(h) => {
+                let shift = index - h;
+                self.seen = if shift >= 64 { 0 } else { self.seen << shift } | 1;
+                self.highest = Some(index);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(index);
+            }
+        }
+    }
+}
+
+/// Length of the RTP header with CSRCs and extension.
+fn header_len(packet: &[u8]) -> Result<usize, SrtpError> {
+    if packet.len() < RTP_HEADER {
+        return Err(SrtpError::Truncated);
+    }
+    let mut len = RTP_HEADER + 4 * (packet[0] & 0x0f) as usize;
+    if packet[0] & 0x10 != 0 {
+        let ext = packet.get(len + 2..len + 4).ok_or(SrtpError::Truncated)?;
+        len += 4 + 4 * u16::from_be_bytes([ext[0], ext[1]]) as usize;
+    }
+    if len > packet.len() {
+        return Err(SrtpError::Truncated);
+    }
+    Ok(len)
+}
+
+fn seq_ssrc(packet: &[u8]) -> (u16, u32) {
+    let seq = u16::from_be_bytes([packet[2], packet[3]]);
+    let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+    (seq, ssrc)
+}
+
+/// SRTP and SRTCP state of one direction.
+struct Context {
+    rtp: SessionKeys,
+    rtcp: SessionKeys,
+    /// rollover counter, the high bits of the packet index
+    roc: u32,
+    /// highest sequence number so far
+    last_seq: Option<u16>,
+    rtcp_index: u32,
+    rtp_replay: ReplayWindow,
+    rtcp_replay: ReplayWindow,
+}
+
+impl Context {
+    fn new(master: &MasterKey) -> Self {
+        Self {
+            rtp: SessionKeys::new(master, 0),
+            rtcp: SessionKeys::new(master, 3),
+            roc: 0,
+            last_seq: None,
+            rtcp_index: 0,
+            rtp_replay: ReplayWindow::default(),
+            rtcp_replay: ReplayWindow::default(),
+        }
+    }
+
+    fn protect_rtp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        let header = header_len(packet)?;
+        let (seq, ssrc) = seq_ssrc(packet);
+        match self.last_seq {
+            Some(last) if seq < last && last - seq > 0x8000 => {
+                self.roc = self.roc.wrapping_add(1);
+                self.last_seq = Some(seq);
+            }
+            Some(last) if (seq.wrapping_sub(last) as i16) <= 0 => {}
+            _ => self.last_seq = Some(seq),
+        }
+        let index = (self.roc as u64) << 16 | seq as u64;
+        let mut out = packet.to_vec();
+        self.rtp.crypt(ssrc, index, &mut out[header..]);
+        let tag = self.rtp.tag(&[&out, &self.roc.to_be_bytes()]);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    /// rollover counter a received `seq` belongs to, RFC 3711 3.3.1
+    fn estimate_roc(&self, seq: u16) -> u32 {
+        let Some(last) = self.last_seq else {
+            return self.roc;
+        };
+        if last < 0x8000 {
+            if seq > last && seq - last > 0x8000 {
+                return self.roc.wrapping_sub(1);
+            }
+        } else if last - 0x8000 > seq {
+            return self.roc.wrapping_add(1);
+        }
+        self.roc
+    }
+
+    fn unprotect_rtp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        let (body, tag) = packet
+            .split_at_checked(packet.len().wrapping_sub(TAG_LEN))
+            .ok_or(SrtpError::Truncated)?;
+        let header = header_len(body)?;
+        let (seq, ssrc) = seq_ssrc(body);
+        let roc = self.estimate_roc(seq);
+        let index = (roc as u64) << 16 | seq as u64;
+        self.rtp_replay.check(index)?;
+        self.rtp.verify(&[body, &roc.to_be_bytes()], tag)?;
+        self.rtp_replay.mark(index);
+        if roc == self.roc.wrapping_add(1) {
+            self.roc = roc;
+            self.last_seq = Some(seq);
+        } else if roc == self.roc && self.last_seq.is_none_or(|last| seq > last) {
+            self.last_seq = Some(seq);
+        }
+        let mut out = body.to_vec();
+        self.rtp.crypt(ssrc, index, &mut out[header..]);
+        Ok(out)
+    }
+
+    fn protect_rtcp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        if packet.len() < RTCP_HEADER {
+            return Err(SrtpError::Truncated);
+        }
+        self.rtcp_index = (self.rtcp_index + 1) & !E_FLAG;
+        let ssrc = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let mut out = packet.to_vec();
+        self.rtcp.crypt(ssrc, self.rtcp_index.into(), &mut out[RTCP_HEADER..]);
+        out.extend_from_slice(&(self.rtcp_index | E_FLAG).to_be_bytes());
+        let tag = self.rtcp.tag(&[&out]);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    fn unprotect_rtcp(&mut self, packet: &[u8]) -> Result<Vec<u8>, SrtpError> {
+        if packet.len() < RTCP_HEADER + 4 + TAG_LEN {
+            return Err(SrtpError::Truncated);
+        }
+        let (signed, tag) = packet.split_at(packet.len() - TAG_LEN);
+        let (body, e_index) = signed.split_at(signed.len() - 4);
+        let e_index = u32::from_be_bytes([e_index[0], e_index[1], e_index[2], e_index[3]]);
+        let index = (e_index & !E_FLAG).into();
+        self.rtcp_replay.check(index)?;
+        self.rtcp.verify(&[signed], tag)?;
+        self.rtcp_replay.mark(index);
+        let mut out = body.to_vec();
+        if e_index & E_FLAG != 0 {
+            let ssrc = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+            self.rtcp.crypt(ssrc, index, &mut out[RTCP_HEADER..]);
+        }
+        Ok(out)
+    }
+}
+
+struct Session {
+    send: Context,
+    recv: Context,
+}
+
+/// SRTP of a call, shared by everything sending or receiving its media;
+/// packets pass through untouched when the call is not encrypted.
+#[derive(Clone, Default)]
+pub struct Srtp(Option<Arc<Mutex<Session>>>);
+
+impl Srtp {
+    pub fn new(keys: Option<SrtpKeys>) -> Self {
+        Self(keys.map(|k| {
+            Arc::new(Mutex::new(Session {
+                send: Context::new(&k.local),
+                recv: Context::new(&k.remote),
+            }))
+        }))
+    }
+
+    fn apply<'a>(
+        &self,
+        packet: &'a [u8],
+        what: &str,
+        f: impl FnOnce(&mut Session, &[u8]) -> Result<Vec<u8>, SrtpError>,
+    ) -> Option<Cow<'a, [u8]>> {
+        let Some(session) = &self.0 else {
+            return Some(Cow::Borrowed(packet));
+        };
+        let mut session = session.lock().ok()?;
+        match f(&mut session, packet) {
+            Ok(p) => Some(Cow::Owned(p)),
+            Err(e) => {
+                info!("Dropping {what} packet: {e}");
+                None
+            }
+        }
+    }
+
+    /// RTP `packet` ready to send, None if it cannot be protected.
+    pub fn protect_rtp<'a>(&self, packet: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        self.apply(packet, "RTP", |s, p| s.send.protect_rtp(p))
+    }
+
+    /// Received RTP, None when it fails authentication.
+    pub fn unprotect_rtp<'a>(&self, packet: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        self.apply(packet, "SRTP", |s, p| s.recv.unprotect_rtp(p))
+    }
+
+    pub fn protect_rtcp<'a>(&self, packet: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        self.apply(packet, "RTCP", |s, p| s.send.protect_rtcp(p))
+    }
+
+    pub fn unprotect_rtcp<'a>(&self, packet: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        self.apply(packet, "SRTCP", |s, p| s.recv.unprotect_rtcp(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn master() -> MasterKey {
+        MasterKey(hex("e1f97a0d3e018be0d64fa32c06de41390ec675ad498afeebb6960b3aabe6").try_into().unwrap())
+    }
+
+    #[test]
+    fn test_key_derivation() {
+        // RFC 3711 B.3
+        let keys = SessionKeys::new(&master(), 0);
+        assert_eq!(keys.cipher.to_vec(), hex("c61e7a93744f39ee10734afe3ff7a087"));
+        assert_eq!(keys.salt.to_vec(), hex("30cbbc08863d8c85d49db34a9ae1"));
+        assert_eq!(keys.auth.to_vec(), hex("cebe321f6ff7716b6fd4ab49af256a156d38baa4"));
+
+        let key = MasterKey::parse(&format!("{}|2^31", master().inline())).unwrap();
+        assert_eq!(key, master());
+        assert_eq!(MasterKey::parse("inline:4OF6DT4Bi+DWT6MsBt5BOQ7Gda1Jiv7rtpYLOqvm|2^20|1:4"), None);
+        assert_eq!(MasterKey::parse("inline:AAAA"), None);
+    }
+
+    #[test]
+    fn test_protect() {
+        // the AES_CM_128_HMAC_SHA1_80 vectors of libsrtp
+        let rtp = hex("800f1234decafbadcafebabeabababababababababababababababab");
+        let srtp = hex("800f1234decafbadcafebabe4e55dc4ce79978d88ca4d215949d2402b78d6acc99ea179b8dbb");
+        let mut tx = Context::new(&master());
+        let mut rx = Context::new(&master());
+        assert_eq!(tx.protect_rtp(&rtp).unwrap(), srtp);
+        assert_eq!(rx.unprotect_rtp(&srtp).unwrap(), rtp);
+        assert_eq!(rx.unprotect_rtp(&srtp), Err(SrtpError::Replay));
+        let mut forged = srtp.clone();
+        forged[20] ^= 1;
+        let mut rx = Context::new(&master());
+        assert_eq!(rx.unprotect_rtp(&forged), Err(SrtpError::Auth));
+
+        let rtcp = hex("81c8000bcafebabeabababababababababababababababab");
+        let srtcp = hex("81c8000bcafebabe7128035be487b9bdbef89041f977a5a880000001993e08cd54d6c1230798");
+        assert_eq!(tx.protect_rtcp(&rtcp).unwrap(), srtcp);
+        assert_eq!(rx.unprotect_rtcp(&srtcp).unwrap(), rtcp);
+
+        // the rollover counter follows sequence numbers across the wrap
+        let (mut tx, mut rx) = (Context::new(&master()), Context::new(&master()));
+        for seq in [0xfffeu16, 0xffff, 0, 1] {
+            let mut p = rtp.clone();
+            p[2..4].copy_from_slice(&seq.to_be_bytes());
+            assert_eq!(rx.unprotect_rtp(&tx.protect_rtp(&p).unwrap()).unwrap(), p);
+        }
+        assert_eq!((tx.roc, rx.roc), (1, 1));
+    }
+}
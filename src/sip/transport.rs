@@ -0,0 +1,109 @@
+use anyhow::{Context, Result, bail};
+use rsip::{Param, Scheme, Transport};
+use rsipstack::transport::{
+    SipAddr, TransportLayer,
+    tcp_listener::TcpListenerConnection,
+    tls::{TlsConfig, TlsListenerConnection},
+};
+use std::{fs, net::SocketAddr};
+
+/// Transport a request to `uri` goes over: TLS for sips, else the
+/// transport parameter, else UDP.
+pub fn of_uri(uri: &rsip::Uri) -> Transport {
+    if uri.scheme == Some(Scheme::Sips) {
+        return Transport::Tls;
+    }
+    uri.params
+        .iter()
+        .find_map(|p| match p {
+            Param::Transport(t) => Some(*t),
+            _ => None,
+        })
+        .unwrap_or(Transport::Udp)
+}
+
+/// Our contact for `user` at `addr`, reachable over `transport`.
+pub fn contact(user: String, addr: rsip::HostWithPort, transport: Transport) -> rsip::Uri {
+    let (scheme, params) = match transport {
+        Transport::Tls => (Scheme::Sips, vec![]),
+        Transport::Udp => (Scheme::Sip, vec![]),
+        t => (Scheme::Sip, vec![Param::Transport(t)]),
+    };
+    rsip::Uri {
+        scheme: Some(scheme),
+        auth: Some(rsip::Auth { user, password: None }),
+        host_with_port: addr,
+        params,
+        headers: vec![],
+    }
+}
+
+/// PEM file contents, with the option it came from in the error.
+fn pem(path: &str, what: &str) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("{what} {path}"))?;
+    if !String::from_utf8_lossy(&data).contains("-----BEGIN ") {
+        bail!("{what} {path} is not PEM");
+    }
+    Ok(data)
+}
+
+/// Certificate and key of the TLS listener, a self-signed pair will do
+/// for local tests.
+pub fn tls_config(cert: Option<&str>, key: Option<&str>) -> Result<TlsConfig> {
+    let (Some(cert), Some(key)) = (cert, key) else {
+        bail!("listening on TLS needs --tls-cert and --tls-key");
+    };
+    Ok(TlsConfig {
+        cert: Some(pem(cert, "TLS certificate")?),
+        key: Some(pem(key, "TLS key")?),
+        ..Default::default()
+    })
+}
+
+/// Listen for SIP over TCP on `local`.
+pub async fn listen_tcp(layer: &TransportLayer, local: SocketAddr, external: Option<SocketAddr>) -> Result<()> {
+    let addr = SipAddr {
+        r#type: Some(Transport::Tcp),
+        addr: local.into(),
+    };
+    let conn = TcpListenerConnection::new(addr, external).await?;
+    layer.add_transport(conn.into());
+    Ok(())
+}
+
+/// Listen for SIP over TLS on `local`.
+pub async fn listen_tls(
+    layer: &TransportLayer,
+    local: SocketAddr,
+    external: Option<SocketAddr>,
+    config: TlsConfig,
+) -> Result<()> {
+    let addr = SipAddr {
+        r#type: Some(Transport::Tls),
+        addr: local.into(),
+    };
+    let conn = TlsListenerConnection::new(addr, external, config).await?;
+    layer.add_transport(conn.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_of_uri() {
+        let uri = |s: &str| rsip::Uri::try_from(s).unwrap();
+        assert_eq!(of_uri(&uri("sip:sip.example.com")), Transport::Udp);
+        assert_eq!(of_uri(&uri("sip:sip.example.com;transport=tcp")), Transport::Tcp);
+        assert_eq!(of_uri(&uri("sip:sip.example.com:5061;transport=tls")), Transport::Tls);
+        assert_eq!(of_uri(&uri("sips:sip.example.com")), Transport::Tls);
+
+        let addr = rsip::HostWithPort::from("192.0.2.1:5061".parse::<SocketAddr>().unwrap());
+        let tls = contact("100".into(), addr.clone(), Transport::Tls);
+        assert_eq!(tls.to_string(), "sips:100@192.0.2.1:5061");
+        for t in [Transport::Udp, Transport::Tcp, Transport::Tls] {
+            assert_eq!(of_uri(&contact("100".into(), addr.clone(), t)), t);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+/// mean square below which a frame is always silence
+const MIN_ENERGY: f32 = 20_000.0;
+/// zero crossing rate above which quiet frames are taken as noise
+const MAX_ZCR: f32 = 0.35;
+/// frames kept as speech after the voice drops
+const HANGOVER_MS: u32 = 200;
+
+/// Energy and zero-crossing voice activity detector for 8 kHz audio.
+///
+/// The noise floor follows the energy of silent frames, so a frame is
+/// speech when it is clearly louder than the line noise and does not
+/// look like hiss.
+#[derive(Debug)]
+pub struct Vad {
+    noise: f32,
+    hangover: u32,
+}
+
+impl Default for Vad {
+    fn default() -> Self {
+        Self {
+            noise: MIN_ENERGY,
+            hangover: 0,
+        }
+    }
+}
+
+impl Vad {
+    pub fn push(&mut self, samples: &[i16]) -> bool {
+        if samples.is_empty() {
+            return false;
+        }
+        let len = samples.len() as f32;
+        let energy = samples
+            .iter()
+            .map(|s| (*s as f32) * (*s as f32))
+            .sum::<f32>()
+            / len;
+        let crossings = samples
+            .windows(2)
+            .filter(|w| (w[0] >= 0) != (w[1] >= 0))
+            .count() as f32
+            / len;
+
+        let floor = self.noise.max(MIN_ENERGY);
+        let voiced = (energy > floor * 4.0 && crossings < MAX_ZCR) || energy > floor * 16.0;
+        let ms = samples.len() as u32 / 8;
+        if voiced {
+            self.hangover = HANGOVER_MS;
+        } else {
+            self.noise = self.noise * 0.95 + energy * 0.05;
+            self.hangover = self.hangover.saturating_sub(ms);
+        }
+        voiced || self.hangover > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vad() {
+        let mut vad = Vad::default();
+        let noise = (0..160).map(|i| if i % 2 == 0 { 60 } else { -60 }).collect::<Vec<i16>>();
+        let voice = (0..160)
+            .map(|i| ((i as f32 / 8000.0 * 2.0 * std::f32::consts::PI * 300.0).sin() * 5000.0) as i16)
+            .collect::<Vec<_>>();
+        assert!((0..50).all(|_| !vad.push(&noise)));
+        assert!(vad.push(&voice));
+        // hangover
+        assert!(vad.push(&noise));
+        assert!((0..20).any(|_| !vad.push(&noise)));
+    }
+}
@@ -6,11 +6,15 @@ use anyhow::{Error, Result};
 // Send SMS notification to a phone number using AWS SNS.
 // The following environment variables need to be defined in the .env file.
 // AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_REGION, AWS_SNS_PHONE_NO, AWS_SNS_SENDER_ID
-pub async fn notify(message: &str) -> Result<()> {
+// A mailbox may publish to its own `topic` instead of AWS_SNS_TOPIC_ARN.
+pub async fn notify(message: &str, topic: Option<&str>) -> Result<()> {
     let sender_id = env::var("AWS_SNS_SENDER_ID")
         .map_err(|e| Error::msg(format!("AWS_SNS_SENDER_ID not found: {e}")))?;
-    let topic_arn = env::var("AWS_SNS_TOPIC_ARN")
-        .map_err(|e| Error::msg(format!("AWS_SNS_TOPIC_ARN not found: {e}")))?;
+    let topic_arn = match topic {
+        Some(topic) => topic.to_owned(),
+        None => env::var("AWS_SNS_TOPIC_ARN")
+            .map_err(|e| Error::msg(format!("AWS_SNS_TOPIC_ARN not found: {e}")))?,
+    };
 
     let config = aws_config::from_env().load().await;
     let client = aws_sdk_sns::Client::new(&config);
@@ -40,5 +44,5 @@ fn test_notify() {
         log::info!("Failed to load .env file: {}", e);
     }
     let rt = tokio::runtime::Runtime::new().unwrap();
-    rt.block_on(notify("test sms targetArn")).expect("TODO: panic message");
+    rt.block_on(notify("test sms targetArn", None)).expect("TODO: panic message");
 }
\ No newline at end of file
@@ -4,6 +4,7 @@ use crate::web::db::{Pool, Queries};
 use anyhow::Result;
 use bytes::Bytes;
 use crate::sip::AiModels;
+use crate::sip::codec::Codec;
 
 mod assemblyai;
 mod gcp;
@@ -17,12 +18,16 @@ pub async fn execute(
         .await?
         .first()
     {
-        Some(Data { data }) => {
-            let pcmu = trim_null_bytes(data);
-            if pcmu.is_empty() {
+        Some(Data { data, codec }) => {
+            let data = trim_null_bytes(data);
+            if data.is_empty() {
                 return Err("no data".into());
             };
-            pcmu
+            // the recognizers are fed with μ-law
+            match Codec::from_name(codec).unwrap_or(Codec::Pcmu) {
+                Codec::Pcmu => data,
+                c => Bytes::from(Codec::Pcmu.transcode(c, &data)),
+            }
         }
         _ => return Err("no data".into()),
     };
@@ -0,0 +1,16 @@
+use super::run;
+
+// Render text with any local synthesizer that reads the text on stdin
+// and writes a WAV file to stdout.
+// The following environment variable needs to be defined in the .env file.
+//
+// TTS_COMMAND: the command line, {voice} is replaced by the voice,
+// ex. mimic3 --voice {voice}
+pub async fn synthesize(text: &str, voice: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let command = std::env::var("TTS_COMMAND").map_err(|_| "TTS_COMMAND not found")?;
+    // split without a shell, so neither the voice nor the text is interpreted
+    let mut words = command.split_whitespace().map(|w| w.replace("{voice}", voice));
+    let program = words.next().ok_or("TTS_COMMAND is empty")?;
+    let args = words.collect::<Vec<_>>();
+    run(&program, &args, text).await
+}
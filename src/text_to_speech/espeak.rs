@@ -0,0 +1,14 @@
+use super::run;
+
+// Render text with eSpeak NG, which runs offline.
+// The following environment variable may be defined in the .env file.
+//
+// ESPEAK_COMMAND: the binary, ex. /usr/bin/espeak-ng (default espeak-ng)
+//
+// The voice is an eSpeak voice name, ex. en, en-us, ja
+pub async fn synthesize(text: &str, voice: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let program = std::env::var("ESPEAK_COMMAND").unwrap_or_else(|_| "espeak-ng".to_string());
+    // the text goes in on stdin as UTF-8, the WAV comes out on stdout
+    let args = ["-b", "1", "-v", voice, "--stdout"].map(String::from);
+    run(&program, &args, text).await
+}
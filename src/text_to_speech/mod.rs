@@ -0,0 +1,103 @@
+use crate::sip::audio_file::{decode_bytes, resample};
+use crate::sip::codec::Codec;
+use crate::web::db::DataType::Data;
+use crate::web::db::{Pool, Queries};
+use clap::ValueEnum;
+use std::{fmt, process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, time::timeout};
+
+mod command;
+mod espeak;
+mod template;
+
+pub use template::{Fields, check, expand, has_fields};
+
+/// Greetings starting with this are spoken text instead of a file name.
+const PREFIX: &str = "tts:";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TtsEngine {
+    /// eSpeak NG
+    #[default]
+    Espeak,
+    /// the synthesizer in TTS_COMMAND
+    Command,
+}
+
+impl fmt::Display for TtsEngine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TtsEngine::Espeak => write!(f, "espeak"),
+            TtsEngine::Command => write!(f, "command"),
+        }
+    }
+}
+
+/// The text of a `tts:` greeting.
+pub fn text(greeting: &str) -> Option<&str> {
+    greeting.strip_prefix(PREFIX)
+}
+
+/// Feed `text` to a synthesizer and collect the WAV it writes.
+async fn run(program: &str, args: &[String], text: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("cannot run {program}: {e}"))?;
+    let mut stdin = child.stdin.take().ok_or("no stdin")?;
+    stdin.write_all(text.as_bytes()).await?;
+    drop(stdin);
+    let output = timeout(TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| format!("{program} timed out"))??;
+    if !output.status.success() {
+        return Err(format!(
+            "{program} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(output.stdout)
+}
+
+/// `text` spoken by `voice` of `engine` as 8 kHz μ-law. With `cache` it
+/// is rendered once and then read from the database, texts naming the
+/// caller or the time would only pile up there.
+pub async fn render(
+    pool: &Pool,
+    engine: TtsEngine,
+    voice: &str,
+    text: &str,
+    cache: bool,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // the same voice name may sound different in another synthesizer
+    let key = format!("{engine}:{voice}");
+    if cache {
+        let cached = crate::web::db::execute(pool, Queries::TtsAudio(text.to_owned(), key.clone())).await?;
+        if let Some(Data { data, .. }) = cached.into_iter().next() {
+            return Ok(data);
+        }
+    }
+    log::info!("tts: {engine} {voice} '{text}'");
+    let wav = match engine {
+        TtsEngine::Espeak => espeak::synthesize(text, voice).await?,
+        TtsEngine::Command => command::synthesize(text, voice).await?,
+    };
+    let pcmu = tokio::task::spawn_blocking(move || {
+        decode_bytes(wav, "wav").map(|pcm| Codec::Pcmu.encode_all(&resample(&pcm.samples, pcm.rate)))
+    })
+    .await??;
+    if pcmu.is_empty() {
+        return Err("no audio".into());
+    }
+    if cache {
+        crate::web::db::execute(pool, Queries::SetTtsAudio(text.to_owned(), key, pcmu.clone())).await?;
+    }
+    Ok(pcmu)
+}
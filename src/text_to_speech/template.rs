@@ -0,0 +1,92 @@
+use chrono::NaiveDateTime;
+
+/// Values substituted into a text greeting.
+#[derive(Debug, Clone, Copy)]
+pub struct Fields<'a> {
+    /// the contact name, or the number when the caller is unknown
+    pub name: &'a str,
+    pub caller: &'a str,
+    pub mailbox: &'a str,
+    pub now: NaiveDateTime,
+}
+
+/// Replace `{name}`, `{caller}`, `{mailbox}`, `{date}`, `{time}` and
+/// `{weekday}`, with `{{` and `}}` for literal braces.
+pub fn expand(template: &str, fields: &Fields) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        if tail.starts_with('}') {
+            return Err("unmatched '}'".into());
+        }
+        let end = tail.find('}').ok_or("unclosed '{'")?;
+        match &tail[1..end] {
+            "name" => out.push_str(fields.name),
+            "caller" => out.push_str(fields.caller),
+            "mailbox" => out.push_str(fields.mailbox),
+            "date" => out.push_str(&fields.now.format("%B %-d").to_string()),
+            "time" => out.push_str(&fields.now.format("%H:%M").to_string()),
+            "weekday" => out.push_str(&fields.now.format("%A").to_string()),
+            key => return Err(format!("unknown placeholder {{{key}}}")),
+        }
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether the text has placeholders, so differs from call to call.
+pub fn has_fields(template: &str) -> bool {
+    template.replace("{{", "").contains('{')
+}
+
+/// Whether the greeting text can be expanded.
+pub fn check(template: &str) -> Result<(), String> {
+    let fields = Fields {
+        name: "",
+        caller: "",
+        mailbox: "",
+        now: NaiveDateTime::default(),
+    };
+    if template.trim().is_empty() {
+        return Err("empty greeting text".into());
+    }
+    expand(template, &fields).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let fields = Fields {
+            name: "Alice",
+            caller: "0312345678",
+            mailbox: "100",
+            now: NaiveDateTime::parse_from_str("2026-10-16 18:05", "%Y-%m-%d %H:%M").unwrap(),
+        };
+        assert_eq!(
+            expand("Hello {name}, it is {weekday} {date} at {time}.", &fields).unwrap(),
+            "Hello Alice, it is Friday October 16 at 18:05."
+        );
+        assert_eq!(expand("{caller} for {mailbox} {{sic}}", &fields).unwrap(), "0312345678 for 100 {sic}");
+        assert_eq!(expand("{nmae}", &fields).unwrap_err(), "unknown placeholder {nmae}");
+        assert!(expand("Hello {name", &fields).is_err());
+        assert!(expand("Hello }", &fields).is_err());
+
+        assert!(check("Leave a message after the beep.").is_ok());
+        assert!(check(" ").is_err());
+
+        assert!(has_fields("Hello {name}"));
+        assert!(has_fields("{{{caller}}}"));
+        assert!(!has_fields("Leave a {{message}}."));
+    }
+}
@@ -1,10 +1,12 @@
-use crate::utils::format_date;
+use crate::utils::{format_date, utc_time};
 use actix_web::{Error, error, web};
 use log::warn;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{MAIN_DB, Statement, params, Transaction};
+use rusqlite::{Connection, MAIN_DB, Statement, params, Transaction};
 use serde::{Deserialize, Serialize};
 use std::io::{Seek, SeekFrom, Write};
+use std::sync::LazyLock;
+use tokio::sync::watch;
 
 pub type Pool = r2d2::Pool<SqliteConnectionManager>;
 pub type R2connection = r2d2::PooledConnection<SqliteConnectionManager>;
@@ -14,56 +16,324 @@ type VoicemailResult = Result<Vec<DataType>, rusqlite::Error>;
 pub enum DataType {
     VoiceList {
         id: i64,
+        mailbox: String,
         event_time: String,
         caller: String,
         tel: String,
         time: u64,
+        codec: String,
+        quality: Option<Quality>,
+        /// listened to over the phone and saved
+        heard: bool,
     },
-    Data { data: Vec<u8>, },
+    Data { data: Vec<u8>, codec: String, },
     Id { id: i64, },
     BlobSize { offset: u64, },
+    Mailbox(Mailbox),
+    GreetingRule(GreetingRule),
+    Contact { caller: String, name: String, },
+    /// who left a message and in which mailbox
+    Caller { caller: String, mailbox: String, },
+    Setting { name: String, value: String, },
+    /// finished messages not yet heard and heard
+    Counts { new: u64, old: u64, },
 }
 
+/// Settings of a mailbox, unset values fall back to the command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Mailbox {
+    pub name: String,
+    pub max_length: Option<u64>,
+    pub min_length: Option<u64>,
+    pub warning: Option<u64>,
+    pub greeting: Option<String>,
+    pub play_greeting: Option<bool>,
+    /// digits entered after '*' during the greeting
+    pub pin: Option<String>,
+    /// caller numbers reaching the message menu without the PIN, comma separated
+    pub owner: Option<String>,
+    /// transcribe messages and send them by SMS
+    pub notify: Option<bool>,
+    /// SNS topic the SMS are published to
+    pub sns_topic: Option<String>,
+    /// speech to text engine, gcp or assemblyai
+    pub ai_models: Option<String>,
+}
+
+/// Receive statistics of a recorded call.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Quality {
+    pub packets: u64,
+    pub lost: u64,
+    /// interarrival jitter in ms
+    pub jitter: f64,
+    /// round trip time from RTCP in ms
+    pub rtt: Option<f64>,
+    pub mos: f64,
+}
+
+/// When a greeting is played instead of the mailbox one, unset
+/// conditions always match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GreetingRule {
+    pub id: Option<i64>,
+    pub greeting: String,
+    pub mailbox: Option<String>,
+    /// a caller number
+    pub caller: Option<String>,
+    /// whether the caller is in the contacts
+    pub known: Option<bool>,
+    /// days of the week, e.g. "mon-fri" or "sat,sun"
+    pub days: Option<String>,
+    /// "HH:MM", the range may wrap past midnight
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    /// "YYYY-MM-DD", inclusive, for out of office
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    pub priority: Option<i64>,
+}
+
+/// Setting naming the greeting played when no mailbox or rule sets one.
+pub const ACTIVE_GREETING: &str = "greeting";
+
 #[allow(clippy::enum_variant_names)]
 pub enum Queries {
     AllVoicemail,
+    MailboxVoicemail(String),
     VoiceData(i64),
+    Caller(i64),
     DeleteVoicemail(i64),
-    InsertData(i64, String, Vec<u8>),
+    /// mailbox, caller, codec and the blob, answered with the new id
+    InsertData(String, String, String, Vec<u8>),
     UpdateSampleTime(i64, u64),
     AddContacts(String, String),
     DeleteContacts(String),
     DeleteBlob(i64),
+    TrimBlob(i64, u64, u64),
+    UpdateQuality(i64, Quality),
+    SetHeard(i64, bool),
+    MessageCounts(String),
+    /// messages stored before there were mailboxes move to this one
+    AdoptMessages(String),
+    AllMailbox,
+    Mailbox(String),
+    SetMailbox(Mailbox),
+    Contact(String),
+    AllGreetingRules,
+    SetGreetingRule(GreetingRule),
+    DeleteGreetingRule(i64),
+    TtsAudio(String, String),
+    SetTtsAudio(String, String, Vec<u8>),
+    Setting(String),
+    SetSetting(String, String),
 }
 
 pub fn all_voicemail(conn: &R2connection) -> VoicemailResult {
     let stmt = conn.prepare("
     SELECT A.id, A.event_time, A.caller as tel,
         COALESCE(B.name, A.caller) AS caller,
-        A.time
+        A.time, A.codec,
+        A.packets, A.lost, A.jitter, A.rtt, A.mos, A.heard, A.mailbox
     FROM voicemail as A
     LEFT JOIN contacts as B
     ON A.caller = B.caller")?;
-    map_stmt_rows(stmt)
+    map_stmt_rows(stmt, params![])
+}
+
+/// Messages of `mailbox`.
+fn mailbox_voicemail(conn: &R2connection, mailbox: &str) -> VoicemailResult {
+    let stmt = conn.prepare("
+    SELECT A.id, A.event_time, A.caller as tel,
+        COALESCE(B.name, A.caller) AS caller,
+        A.time, A.codec,
+        A.packets, A.lost, A.jitter, A.rtt, A.mos, A.heard, A.mailbox
+    FROM voicemail as A
+    LEFT JOIN contacts as B
+    ON A.caller = B.caller
+    WHERE A.mailbox = (?1)")?;
+    map_stmt_rows(stmt, [mailbox])
+}
+
+fn all_mailbox(conn: &R2connection) -> VoicemailResult {
+    let mut stmt = conn.prepare("
+    SELECT name, max_length, min_length, warning, greeting, play_greeting, pin, owner,
+        notify, sns_topic, ai_models
+    FROM mailbox ORDER BY name")?;
+    stmt.query_map([], map_mailbox)
+        .and_then(Iterator::collect)
+}
+
+fn mailbox(conn: &R2connection, name: &str) -> VoicemailResult {
+    let mut stmt = conn.prepare("
+    SELECT name, max_length, min_length, warning, greeting, play_greeting, pin, owner,
+        notify, sns_topic, ai_models
+    FROM mailbox WHERE name = (?1)")?;
+    stmt.query_map([name], map_mailbox)
+        .and_then(Iterator::collect)
+}
+
+fn map_mailbox(row: &rusqlite::Row) -> Result<DataType, rusqlite::Error> {
+    Ok(DataType::Mailbox(Mailbox {
+        name: row.get(0)?,
+        max_length: row.get(1)?,
+        min_length: row.get(2)?,
+        warning: row.get(3)?,
+        greeting: row.get(4)?,
+        play_greeting: row.get(5)?,
+        pin: row.get(6)?,
+        owner: row.get(7)?,
+        notify: row.get(8)?,
+        sns_topic: row.get(9)?,
+        ai_models: row.get(10)?,
+    }))
 }
 
-fn map_stmt_rows(mut stmt: Statement) -> VoicemailResult {
+fn set_mailbox(conn: &R2connection, m: &Mailbox) -> VoicemailResult {
+    conn.execute(
+        "INSERT INTO mailbox (name, max_length, min_length, warning, greeting, play_greeting, pin, owner,
+            notify, sns_topic, ai_models)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+        ON CONFLICT(name) DO UPDATE SET
+            max_length = excluded.max_length,
+            min_length = excluded.min_length,
+            warning = excluded.warning,
+            greeting = excluded.greeting,
+            play_greeting = excluded.play_greeting,
+            pin = excluded.pin,
+            owner = excluded.owner,
+            notify = excluded.notify,
+            sns_topic = excluded.sns_topic,
+            ai_models = excluded.ai_models",
+        params![m.name, m.max_length, m.min_length, m.warning, m.greeting, m.play_greeting,
+            m.pin, m.owner, m.notify, m.sns_topic, m.ai_models],
+    )?;
+    all_mailbox(conn)
+}
+
+fn contact(conn: &R2connection, caller: &str) -> VoicemailResult {
+    let mut stmt = conn.prepare("SELECT caller, name FROM contacts WHERE caller = (?1)")?;
+    stmt.query_map([caller], |row| {
+        Ok(DataType::Contact { caller: row.get(0)?, name: row.get(1)? })
+    })
+    .and_then(Iterator::collect)
+}
+
+fn all_greeting_rules(conn: &R2connection) -> VoicemailResult {
+    let mut stmt = conn.prepare("
+    SELECT id, greeting, mailbox, caller, known, days,
+        start_time, end_time, start_date, end_date, priority
+    FROM greeting_rule ORDER BY id")?;
     stmt.query_map([], |row| {
+        Ok(DataType::GreetingRule(GreetingRule {
+            id: row.get(0)?,
+            greeting: row.get(1)?,
+            mailbox: row.get(2)?,
+            caller: row.get(3)?,
+            known: row.get(4)?,
+            days: row.get(5)?,
+            start_time: row.get(6)?,
+            end_time: row.get(7)?,
+            start_date: row.get(8)?,
+            end_date: row.get(9)?,
+            priority: row.get(10)?,
+        }))
+    })
+    .and_then(Iterator::collect)
+}
+
+fn set_greeting_rule(conn: &R2connection, r: &GreetingRule) -> VoicemailResult {
+    conn.execute(
+        "INSERT INTO greeting_rule (id, greeting, mailbox, caller, known, days,
+            start_time, end_time, start_date, end_date, priority)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+        ON CONFLICT(id) DO UPDATE SET
+            greeting = excluded.greeting,
+            mailbox = excluded.mailbox,
+            caller = excluded.caller,
+            known = excluded.known,
+            days = excluded.days,
+            start_time = excluded.start_time,
+            end_time = excluded.end_time,
+            start_date = excluded.start_date,
+            end_date = excluded.end_date,
+            priority = excluded.priority",
+        params![r.id, r.greeting, r.mailbox, r.caller, r.known, r.days,
+            r.start_time, r.end_time, r.start_date, r.end_date, r.priority],
+    )?;
+    all_greeting_rules(conn)
+}
+
+fn delete_greeting_rule(conn: &R2connection, id: i64) -> VoicemailResult {
+    conn.execute("DELETE FROM greeting_rule WHERE id = (?1)", [id])?;
+    all_greeting_rules(conn)
+}
+
+fn tts_audio(conn: &R2connection, text: &str, voice: &str) -> VoicemailResult {
+    let mut stmt = conn.prepare("SELECT data FROM tts_cache WHERE text = (?1) AND voice = (?2)")?;
+    stmt.query_map([text, voice], |row| {
+        Ok(DataType::Data { data: row.get(0)?, codec: "PCMU".to_string() })
+    })
+    .and_then(Iterator::collect)
+}
+
+fn set_tts_audio(conn: &R2connection, text: &str, voice: &str, data: &[u8]) -> VoicemailResult {
+    conn.execute(
+        "INSERT OR REPLACE INTO tts_cache (text, voice, data) VALUES (?1, ?2, ?3)",
+        params![text, voice, data],
+    )?;
+    Ok(vec![])
+}
+
+fn setting(conn: &R2connection, name: &str) -> VoicemailResult {
+    let mut stmt = conn.prepare("SELECT name, value FROM settings WHERE name = (?1)")?;
+    stmt.query_map([name], |row| {
+        Ok(DataType::Setting { name: row.get(0)?, value: row.get(1)? })
+    })
+    .and_then(Iterator::collect)
+}
+
+fn set_setting(conn: &R2connection, name: &str, value: &str) -> VoicemailResult {
+    conn.execute(
+        "INSERT OR REPLACE INTO settings (name, value) VALUES (?1, ?2)",
+        [name, value],
+    )?;
+    setting(conn, name)
+}
+
+fn map_stmt_rows<P: rusqlite::Params>(mut stmt: Statement, params: P) -> VoicemailResult {
+    stmt.query_map(params, |row| {
         Ok(DataType::VoiceList {
             id: row.get(0)?,
+            mailbox: row.get(12).unwrap_or_default(),
             event_time: row.get(1)?,
             tel: row.get(2)?,
             caller: row.get(3)?,
             time: row.get(4).unwrap_or_default(),
+            codec: row.get(5).unwrap_or_else(|_| "PCMU".to_string()),
+            quality: row.get::<_, Option<i64>>(6)?.map(|packets| Quality {
+                packets: packets as u64,
+                lost: row.get::<_, Option<i64>>(7).ok().flatten().unwrap_or_default() as u64,
+                jitter: row.get::<_, Option<f64>>(8).ok().flatten().unwrap_or_default(),
+                rtt: row.get(9).ok().flatten(),
+                mos: row.get::<_, Option<f64>>(10).ok().flatten().unwrap_or_default(),
+            }),
+            heard: row.get(11).unwrap_or_default(),
         })
     })
     .and_then(Iterator::collect)
 }
 
 fn voice_data(conn: &R2connection, id: i64) -> VoicemailResult {
-    let data = conn.query_row("SELECT data FROM voicemail WHERE id = (?1)",
-                              [id], |row| { row.get(0) })?;
-    Ok(vec![DataType::Data { data }])
+    let (data, codec) = conn.query_row("SELECT data, codec FROM voicemail WHERE id = (?1)",
+                              [id], |row| { Ok((row.get(0)?, row.get(1)?)) })?;
+    Ok(vec![DataType::Data { data, codec }])
+}
+
+/// Who left message `id` and in which mailbox.
+fn caller(conn: &R2connection, id: i64) -> VoicemailResult {
+    conn.query_row("SELECT caller, mailbox FROM voicemail WHERE id = (?1)",
+        [id], |row| Ok(vec![DataType::Caller { caller: row.get(0)?, mailbox: row.get(1)? }]))
 }
 
 fn del_voicemail(conn: &R2connection, id: i64) -> VoicemailResult {
@@ -79,14 +349,53 @@ fn del_blob(conn: &R2connection, id: i64) -> VoicemailResult {
     Ok(vec![DataType::Id { id }])
 }
 
-fn insert_data(conn: &R2connection, id: i64, caller: &str, data: &[u8]) -> VoicemailResult {
+fn update_quality(conn: &R2connection, id: i64, q: &Quality) -> VoicemailResult {
     conn.execute(
-        "INSERT INTO voicemail (id, event_time, caller, data) VALUES (?1, ?2, ?3, ?4)",
-        params![id, format_date(id), caller, data],
+        "UPDATE voicemail SET packets = (?2), lost = (?3), jitter = (?4), rtt = (?5), mos = (?6)
+        WHERE id = (?1)",
+        params![id, q.packets as i64, q.lost as i64, q.jitter, q.rtt, q.mos],
     )?;
     Ok(vec![DataType::Id { id }])
 }
 
+fn set_heard(conn: &R2connection, id: i64, heard: bool) -> VoicemailResult {
+    conn.execute(
+        "UPDATE voicemail SET heard = (?2) WHERE id = (?1)",
+        params![id, heard],
+    )?;
+    Ok(vec![DataType::Id { id }])
+}
+
+fn message_counts(conn: &R2connection, mailbox: &str) -> VoicemailResult {
+    conn.query_row("
+    SELECT COALESCE(SUM(heard = 0), 0), COALESCE(SUM(heard != 0), 0)
+    FROM voicemail WHERE time > 0 AND mailbox = (?1)",
+        [mailbox], |row| Ok(vec![DataType::Counts { new: row.get(0)?, old: row.get(1)? }]))
+}
+
+fn adopt_messages(conn: &R2connection, mailbox: &str) -> VoicemailResult {
+    conn.execute("UPDATE voicemail SET mailbox = (?1) WHERE mailbox = ''", [mailbox])?;
+    Ok(vec![])
+}
+
+fn trim_blob(conn: &R2connection, id: i64, offset: u64, len: u64) -> VoicemailResult {
+    conn.execute(
+        "UPDATE voicemail SET data = substr(data, ?2 + 1, ?3) WHERE id = (?1)",
+        params![id, offset as i64, len as i64],
+    )?;
+    Ok(vec![DataType::Id { id }])
+}
+
+fn insert_data(conn: &R2connection, mailbox: &str, caller: &str, codec: &str, data: &[u8]) -> VoicemailResult {
+    // the id is left to SQLite, calls in the same second would collide
+    let now = utc_time().parse::<i64>().expect("timestamp");
+    conn.execute(
+        "INSERT INTO voicemail (mailbox, event_time, caller, codec, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![mailbox, format_date(now), caller, codec, data],
+    )?;
+    Ok(vec![DataType::Id { id: conn.last_insert_rowid() }])
+}
+
 fn insert_contacts(conn: &R2connection, caller: &str, name: &str) -> Result<usize, rusqlite::Error> {
     conn.execute(
         "INSERT INTO contacts (caller, name) VALUES (?1, ?2)",
@@ -127,15 +436,54 @@ fn add_contacts(conn: &R2connection, caller: &str, name: &str) -> VoicemailResul
     all_voicemail(conn)
 }
 
+/// Add columns introduced after the table was first created.
+pub fn add_columns(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (name, decl) in columns {
+        if !exists.iter().any(|c| c == name) {
+            conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {name} {decl}"), [])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn reset_blob(conn: &R2connection, id: i64, size: usize) -> Result<usize, rusqlite::Error> {
+    conn.execute(
+        "UPDATE voicemail SET data = zeroblob(?2) WHERE id = (?1)",
+        params![id, size as i64],
+    )
+}
+
 pub fn append_chunk_blob(
     conn: &R2connection,
     id: i64,
     offset: u64,
     data: &[u8],
 ) -> Result<u64, rusqlite::Error> {
+    write_blob(conn, id, offset, data)
+}
+
+/// Write `data` at `offset` of the message, growing its blob when the
+/// recording runs past the end.
+fn write_blob(conn: &Connection, id: i64, offset: u64, data: &[u8]) -> Result<u64, rusqlite::Error> {
     let rowid = conn.query_row("SELECT rowid FROM voicemail WHERE id = (?1)",
                                [id], |row| { row.get(0) })?;
     let mut blob = conn.blob_open(MAIN_DB, "voicemail", "data", rowid, false)?;
+    let len = blob.len() as u64;
+    let end = offset + data.len() as u64;
+    if end > len {
+        drop(blob);
+        // at least doubled, so a long message is not copied on every frame
+        let grow = (end - len).max(len);
+        conn.execute(
+            "UPDATE voicemail SET data = CAST(data || zeroblob(?2) AS BLOB) WHERE rowid = (?1)",
+            params![rowid, grow as i64],
+        )?;
+        blob = conn.blob_open(MAIN_DB, "voicemail", "data", rowid, false)?;
+    }
 
     match blob.seek(SeekFrom::Start(offset)) {
         Ok(_) => {
@@ -149,8 +497,18 @@ pub fn append_chunk_blob(
     }
 }
 
+/// Bumped whenever a message is finished, heard or deleted.
+static CHANGES: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::channel(0).0);
+
+/// Notified after each change to the message counts, whoever made it.
+pub fn message_changes() -> watch::Receiver<u64> {
+    CHANGES.subscribe()
+}
+
 pub async fn execute(pool: &Pool, query: Queries) -> Result<Vec<DataType>, Error> {
     let pool = pool.clone();
+    let changes = matches!(query,
+        Queries::DeleteVoicemail(_) | Queries::UpdateSampleTime(..) | Queries::SetHeard(..));
 
     let conn = web::block(move || pool.get())
         .await?
@@ -159,10 +517,12 @@ pub async fn execute(pool: &Pool, query: Queries) -> Result<Vec<DataType>, Error
     web::block(move || {
         match query {
             Queries::AllVoicemail => all_voicemail(&conn),
+            Queries::MailboxVoicemail(mailbox) => mailbox_voicemail(&conn, &mailbox),
             Queries::VoiceData(id) => voice_data(&conn, id),
+            Queries::Caller(id) => caller(&conn, id),
             Queries::DeleteVoicemail(id) => del_voicemail(&conn, id),
-            Queries::InsertData(id, caller, data)
-                => insert_data(&conn, id, &caller, &data),
+            Queries::InsertData(mailbox, caller, codec, data)
+                => insert_data(&conn, &mailbox, &caller, &codec, &data),
             Queries::UpdateSampleTime(id, time)
                 => update_sample_time(&conn, id, time),
             Queries::AddContacts(caller, name)
@@ -171,10 +531,44 @@ pub async fn execute(pool: &Pool, query: Queries) -> Result<Vec<DataType>, Error
                 => delete_contacts(&conn, &caller),
             Queries::DeleteBlob(id)
                 => del_blob(&conn, id),
+            Queries::TrimBlob(id, offset, len)
+                => trim_blob(&conn, id, offset, len),
+            Queries::UpdateQuality(id, quality)
+                => update_quality(&conn, id, &quality),
+            Queries::SetHeard(id, heard) => set_heard(&conn, id, heard),
+            Queries::MessageCounts(mailbox) => message_counts(&conn, &mailbox),
+            Queries::AdoptMessages(mailbox) => adopt_messages(&conn, &mailbox),
+            Queries::AllMailbox => all_mailbox(&conn),
+            Queries::Mailbox(name) => mailbox(&conn, &name),
+            Queries::SetMailbox(m) => set_mailbox(&conn, &m),
+            Queries::Contact(caller) => contact(&conn, &caller),
+            Queries::AllGreetingRules => all_greeting_rules(&conn),
+            Queries::SetGreetingRule(r) => set_greeting_rule(&conn, &r),
+            Queries::DeleteGreetingRule(id) => delete_greeting_rule(&conn, id),
+            Queries::TtsAudio(text, voice) => tts_audio(&conn, &text, &voice),
+            Queries::SetTtsAudio(text, voice, data)
+                => set_tts_audio(&conn, &text, &voice, &data),
+            Queries::Setting(name) => setting(&conn, &name),
+            Queries::SetSetting(name, value) => set_setting(&conn, &name, &value),
         }
     })
     .await?
     .map_err(error::ErrorInternalServerError)
+    .inspect(|_| {
+        if changes {
+            CHANGES.send_modify(|n| *n += 1);
+        }
+    })
+}
+
+/// The greeting marked active in the web UI for all mailboxes, played
+/// by those without a greeting of their own.
+pub async fn active_greeting(pool: &Pool) -> Option<String> {
+    let rows = execute(pool, Queries::Setting(ACTIVE_GREETING.to_owned())).await.ok()?;
+    rows.into_iter().find_map(|r| match r {
+        DataType::Setting { value, .. } => Some(value),
+        _ => None,
+    })
 }
 
 #[allow(unused)]
@@ -184,27 +578,15 @@ pub fn tx_append_chunk_blob(
     offset: u64,
     data: &[u8],
 ) -> Result<u64, rusqlite::Error> {
-    let rowid = conn.query_row("SELECT rowid FROM voicemail WHERE id = (?1)",
-                               [id], |row| { row.get(0) })?;
-    let mut blob = conn.blob_open(MAIN_DB, "voicemail", "data", rowid, false)?;
-
-    match blob.seek(SeekFrom::Start(offset)) {
-        Ok(_) => {
-            let bytes_written = blob.write(data).expect("blob write") as u64;
-            Ok(offset + bytes_written)
-        }
-        Err(e) => {
-            warn!("{e:?}");
-            Ok(offset)
-        }
-    }
+    write_blob(conn, id, offset, data)
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::Instant;
-    use crate::utils::{chunked, file_open, utc_time};
-    use crate::web::db::{Pool, Queries, execute, tx_append_chunk_blob};
+    use crate::utils::{chunked, file_open};
+    use crate::web::db::DataType;
+    use crate::web::db::{Pool, Queries, append_chunk_blob, execute, tx_append_chunk_blob};
     use r2d2_sqlite::SqliteConnectionManager;
     #[actix_web::test]
     async fn test_blob() {
@@ -213,13 +595,15 @@ mod tests {
 
         let zero_blob: Vec<u8> = vec![0; 3000000];
         let data = file_open("recv_voice/recv_20250913013410_102.au").expect("file open");
-        let id = utc_time().parse::<i64>().unwrap();
-
         let caller = "test caller".to_string();
 
-        let result = execute(&pool, Queries::InsertData(id, caller, zero_blob))
+        let result = execute(&pool, Queries::InsertData("100".to_string(), caller, "PCMU".to_string(), zero_blob))
             .await
             .expect("exec");
+        let Some(DataType::Id { id }) = result.first() else {
+            panic!("no id");
+        };
+        let id = *id;
         let mut con = pool.get().unwrap();
         let tx = con.transaction().unwrap();
         let mut n = 0;
@@ -236,4 +620,50 @@ mod tests {
 
         println!("{result:?}");
     }
+
+    #[test]
+    fn test_blob_grows() {
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        let con = pool.get().unwrap();
+        con.execute("CREATE TABLE voicemail (id INTEGER PRIMARY KEY, data BLOB)", []).unwrap();
+        con.execute("INSERT INTO voicemail (id, data) VALUES (1, zeroblob(300000))", []).unwrap();
+
+        // 40 s of G.711, past the 300000 bytes every message used to get
+        let frame = [0x55u8; 160];
+        let mut n = 0;
+        for _ in 0..2000 {
+            n = append_chunk_blob(&con, 1, n, &frame).unwrap();
+        }
+        assert_eq!(n, 320000);
+        let data: Vec<u8> = con
+            .query_row("SELECT substr(data, 1, ?1) FROM voicemail WHERE id = 1", [n as i64], |r| r.get(0))
+            .unwrap();
+        assert_eq!(data.len(), 320000);
+        assert!(data.iter().all(|b| *b == 0x55));
+    }
+
+    #[actix_web::test]
+    async fn test_insert_ids() {
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        pool.get()
+            .unwrap()
+            .execute(
+                "CREATE TABLE voicemail (id INTEGER PRIMARY KEY, mailbox TEXT, event_time TEXT,
+                caller TEXT, codec TEXT, data BLOB)",
+                [],
+            )
+            .unwrap();
+        // two calls within the same second
+        let mut ids = vec![];
+        for caller in ["101", "102"] {
+            let rows = execute(&pool, Queries::InsertData("100".into(), caller.into(), "PCMU".into(), vec![0; 160]))
+                .await
+                .unwrap();
+            let Some(DataType::Id { id }) = rows.first() else {
+                panic!("no id");
+            };
+            ids.push(*id);
+        }
+        assert_ne!(ids[0], ids[1]);
+    }
 }
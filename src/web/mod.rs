@@ -1,19 +1,30 @@
 use actix_web::cookie::ParseError::EmptyName;
-use actix_web::error::{ErrorInternalServerError, ErrorNotFound, ParseError};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, ParseError};
 use actix_web::http::StatusCode;
-use actix_web::http::header::{ContentDisposition, ContentType};
-use actix_web::{App, Error as AcError, Error, HttpResponse, HttpServer, Result as AcResult, get, middleware, web, put};
+use actix_web::http::header::{CONTENT_TYPE, ContentDisposition, ContentType};
+use actix_web::{App, Error as AcError, Error, HttpRequest, HttpResponse, HttpServer, Result as AcResult, delete, get, middleware, web, post, put};
 use anyhow::Result;
+use clap::ValueEnum;
 use std::io;
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
+use crate::sip::AiModels;
+use crate::sip::audio_file::{self, AudioError};
+use crate::sip::call::Calls;
+use crate::sip::dial::Dialer;
+use crate::sip::register::RegStatus;
+use crate::sip::greeting::{self, Schedule};
+use crate::sip::codec::Codec;
 use crate::utils::{open, trim_null_bytes};
 use db::DataType::Data;
-use db::{Queries, execute};
+use db::{ACTIVE_GREETING, GreetingRule, Mailbox, Queries, active_greeting, execute};
 use db::Pool;
 
 pub mod db;
 
+/// largest greeting upload, about 15 minutes of 16 bit 8 kHz WAV
+const UPLOAD_LIMIT: usize = 16 * 1024 * 1024;
+
 #[get("/")]
 async fn index() -> AcResult<HttpResponse> {
     let p = String::from("assets/web/index.html");
@@ -34,9 +45,19 @@ async fn sip_html(html: web::Path<String>) -> AcResult<HttpResponse> {
         .body(x))
 }
 
+/// `?mailbox=` narrowing a list to one mailbox.
+#[derive(Debug, Deserialize)]
+pub struct MailboxQuery {
+    pub mailbox: Option<String>,
+}
+
 #[get("/api/all")]
-async fn voicemail_all(db: web::Data<Pool>) -> Result<HttpResponse, AcError> {
-    match execute(&db, Queries::AllVoicemail).await {
+async fn voicemail_all(db: web::Data<Pool>, query: web::Query<MailboxQuery>) -> Result<HttpResponse, AcError> {
+    let query = match query.into_inner().mailbox {
+        Some(mailbox) => Queries::MailboxVoicemail(mailbox),
+        None => Queries::AllVoicemail,
+    };
+    match execute(&db, query).await {
         Ok(result) => Ok(HttpResponse::Ok().json(result)),
         Err(e) => {
             log::info!("list empty {e:?}");
@@ -45,6 +66,225 @@ async fn voicemail_all(db: web::Data<Pool>) -> Result<HttpResponse, AcError> {
     }
 }
 
+#[get("/api/mailbox")]
+async fn mailbox_all(db: web::Data<Pool>) -> Result<HttpResponse, AcError> {
+    let result = execute(&db, Queries::AllMailbox).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[put("/api/mailbox")]
+async fn modify_mailbox(
+    db: web::Data<Pool>,
+    item: web::Json<Mailbox>,
+) -> Result<HttpResponse, AcError> {
+    log::info!("{item:?}");
+    if item.pin.as_ref().is_some_and(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_digit())) {
+        return Err(ErrorBadRequest("the PIN must be digits"));
+    }
+    if let Some(ai_models) = &item.ai_models
+        && AiModels::from_str(ai_models, true).is_err()
+    {
+        return Err(ErrorBadRequest(format!("unknown speech to text engine {ai_models}")));
+    }
+    if let Some(greeting) = item.greeting.clone() {
+        web::block(move || greeting::validate_stored(&greeting, &[Codec::Pcmu, Codec::Pcma]))
+            .await?
+            .map_err(ErrorBadRequest)?;
+    }
+    let result = execute(&db, Queries::SetMailbox(item.into_inner())).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/api/greeting_rules")]
+async fn greeting_rules_all(db: web::Data<Pool>) -> Result<HttpResponse, AcError> {
+    let result = execute(&db, Queries::AllGreetingRules).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[put("/api/greeting_rules")]
+async fn modify_greeting_rule(
+    db: web::Data<Pool>,
+    item: web::Json<GreetingRule>,
+) -> Result<HttpResponse, AcError> {
+    log::info!("{item:?}");
+    Schedule::parse(&item).map_err(ErrorBadRequest)?;
+    let greeting = item.greeting.clone();
+    web::block(move || greeting::validate_stored(&greeting, &[Codec::Pcmu, Codec::Pcma]))
+        .await?
+        .map_err(ErrorBadRequest)?;
+    let result = execute(&db, Queries::SetGreetingRule(item.into_inner())).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[delete("/api/greeting_rules/{id}")]
+async fn del_greeting_rule(
+    db: web::Data<Pool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AcError> {
+    let result = execute(&db, Queries::DeleteGreetingRule(path.into_inner())).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// A greeting file, `duration` in seconds is unset when it cannot be decoded.
+#[derive(Debug, Serialize)]
+pub struct GreetingFile {
+    pub name: String,
+    pub duration: Option<f64>,
+    pub uploaded: bool,
+    pub active: bool,
+}
+
+/// Settings of mailbox `name`, empty when it has none.
+async fn mailbox_settings(pool: &Pool, name: &str) -> Result<Mailbox, AcError> {
+    let rows = execute(pool, Queries::Mailbox(name.to_owned())).await?;
+    Ok(rows
+        .into_iter()
+        .find_map(|r| match r {
+            db::DataType::Mailbox(m) => Some(m),
+            _ => None,
+        })
+        .unwrap_or_else(|| Mailbox { name: name.to_owned(), ..Default::default() }))
+}
+
+/// Greeting files, the active one being that of `mailbox` when given.
+async fn greeting_files(pool: &Pool, mailbox: Option<&str>) -> Result<Vec<GreetingFile>, AcError> {
+    let active = match mailbox {
+        Some(mailbox) => mailbox_settings(pool, mailbox).await?.greeting,
+        None => active_greeting(pool).await,
+    };
+    let files = web::block(|| {
+        audio_file::list()
+            .into_iter()
+            .map(|(name, uploaded)| {
+                // also fills the cache for the first call
+                let duration = audio_file::load(&name, Codec::Pcmu)
+                    .map(|d| d.len() as f64 / 8000.0)
+                    .inspect_err(|e| log::info!("{e}"))
+                    .ok();
+                (name, duration, uploaded)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await?;
+    Ok(files
+        .into_iter()
+        .map(|(name, duration, uploaded)| GreetingFile {
+            active: active.as_deref() == Some(name.as_str()),
+            name,
+            duration,
+            uploaded,
+        })
+        .collect())
+}
+
+#[get("/api/greetings")]
+async fn greetings_all(db: web::Data<Pool>, query: web::Query<MailboxQuery>) -> Result<HttpResponse, AcError> {
+    Ok(HttpResponse::Ok().json(greeting_files(&db, query.mailbox.as_deref()).await?))
+}
+
+/// Audio format of an upload, from its content type.
+fn upload_ext(req: &HttpRequest) -> Option<&'static str> {
+    let mime = req.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+    match mime.split(';').next()?.trim() {
+        "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some("wav"),
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/basic" | "audio/au" => Some("au"),
+        _ => None,
+    }
+}
+
+#[put("/api/greetings/{name}")]
+async fn upload_greeting(
+    db: web::Data<Pool>,
+    path: web::Path<String>,
+    query: web::Query<MailboxQuery>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, AcError> {
+    let name = path.into_inner();
+    if !audio_file::valid_name(&name) {
+        return Err(ErrorBadRequest(AudioError::InvalidName(name)));
+    }
+    let ext = upload_ext(&req)
+        .ok_or_else(|| ErrorBadRequest("unsupported content type, upload WAV, MP3, FLAC or AU"))?;
+    log::info!("upload greeting {name}: {ext} {} bytes", body.len());
+    web::block(move || {
+        let pcm = audio_file::decode_bytes(body.to_vec(), ext)?;
+        let samples = audio_file::resample(&pcm.samples, pcm.rate);
+        if samples.is_empty() {
+            return Err("no audio".to_owned());
+        }
+        audio_file::save(&name, &samples).map_err(|e| e.to_string())
+    })
+    .await?
+    .map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(greeting_files(&db, query.mailbox.as_deref()).await?))
+}
+
+/// Make greeting `name` the one played, of `?mailbox=` when given, else
+/// of every mailbox without a greeting of its own.
+#[put("/api/greetings/{name}/active")]
+async fn activate_greeting(
+    db: web::Data<Pool>,
+    path: web::Path<String>,
+    query: web::Query<MailboxQuery>,
+) -> Result<HttpResponse, AcError> {
+    let name = path.into_inner();
+    let greeting = name.clone();
+    web::block(move || greeting::validate_stored(&greeting, &[Codec::Pcmu, Codec::Pcma]))
+        .await?
+        .map_err(ErrorBadRequest)?;
+    let mailbox = query.into_inner().mailbox;
+    match &mailbox {
+        Some(mailbox) => {
+            log::info!("greeting of mailbox {mailbox}: {name}");
+            let settings = mailbox_settings(&db, mailbox).await?;
+            execute(&db, Queries::SetMailbox(Mailbox { greeting: Some(name), ..settings })).await?;
+        }
+        None => {
+            log::info!("active greeting: {name}");
+            execute(&db, Queries::SetSetting(ACTIVE_GREETING.to_owned(), name)).await?;
+        }
+    }
+    Ok(HttpResponse::Ok().json(greeting_files(&db, mailbox.as_deref()).await?))
+}
+
+#[get("/api/calls")]
+async fn active_calls(calls: web::Data<Calls>) -> Result<HttpResponse, AcError> {
+    Ok(HttpResponse::Ok().json(calls.list().await))
+}
+
+/// Ring the user's extension, then call the caller of message `id` back.
+#[post("/api/call/{id}")]
+async fn call_back(
+    db: web::Data<Pool>,
+    dialer: web::Data<Dialer>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AcError> {
+    let id = path.into_inner();
+    let (number, mailbox) = match execute(&db, Queries::Caller(id)).await?.into_iter().next() {
+        Some(db::DataType::Caller { caller, mailbox }) => (caller, mailbox),
+        _ => return Err(ErrorNotFound(format!("no message {id}"))),
+    };
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || "+*#".contains(c)) {
+        return Err(ErrorBadRequest(format!("{number:?} cannot be called back")));
+    }
+    log::info!("call back message {id}: {number}");
+    let dial = dialer.dial(id, mailbox, number).await.map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(dial))
+}
+
+#[get("/api/dials")]
+async fn dials(dialer: web::Data<Dialer>) -> Result<HttpResponse, AcError> {
+    Ok(HttpResponse::Ok().json(dialer.list().await))
+}
+
+#[get("/api/registration")]
+async fn registration(status: web::Data<RegStatus>) -> Result<HttpResponse, AcError> {
+    Ok(HttpResponse::Ok().json(status.list().await))
+}
+
 #[get("/{path}/{file}")]
 async fn assets(assets: web::Path<(String, String)>) -> Result<HttpResponse, AcError> {
     let (path, file) = assets.into_inner();
@@ -70,9 +310,9 @@ async fn assets(assets: web::Path<(String, String)>) -> Result<HttpResponse, AcE
 #[get("/api/del/{id}")]
 async fn del_voicemail(
     db: web::Data<Pool>,
-    path: web::Path<String>,
+    path: web::Path<i64>,
 ) -> Result<HttpResponse, AcError> {
-    let id = path.into_inner().parse::<i64>().expect("delete voicemail");
+    let id = path.into_inner();
     let result = execute(&db, Queries::DeleteVoicemail(id)).await?;
     Ok(HttpResponse::Ok().json(result))
 }
@@ -100,14 +340,17 @@ async fn modify_caller (
 }
 
 #[get("/api/voice/{id}")]
-async fn voice_data(db: web::Data<Pool>, path: web::Path<String>) -> Result<HttpResponse, AcError> {
-    let id = path.into_inner().parse::<i64>().expect("get voice data");
+async fn voice_data(db: web::Data<Pool>, path: web::Path<i64>) -> Result<HttpResponse, AcError> {
+    let id = path.into_inner();
     match execute(&db, Queries::VoiceData(id)).await?.first() {
-        Some(Data { data }) => {
+        Some(Data { data, codec }) => {
             let d = trim_null_bytes(data);
-            let cd = ContentDisposition::attachment(format!("{}.au", id));
+            let codec = Codec::from_name(codec).unwrap_or(Codec::Pcmu);
+            let cd = ContentDisposition::attachment(format!("{}.{}", id, codec.ext()));
+            // played in the browser, so no longer waiting
+            execute(&db, Queries::SetHeard(id, true)).await?;
             Ok(HttpResponse::Ok()
-                .content_type("audio/basic")
+                .content_type(codec.mime())
                 .append_header(cd)
                 .body(d))
         }
@@ -115,7 +358,7 @@ async fn voice_data(db: web::Data<Pool>, path: web::Path<String>) -> Result<Http
     }
 }
 
-pub async fn server(pool: Pool) -> io::Result<()> {
+pub async fn server(pool: Pool, calls: Calls, dialer: Dialer, registration_status: RegStatus) -> io::Result<()> {
     log::info!("starting HTTP server at http://localhost:8080");
 
     // start HTTP server
@@ -123,12 +366,28 @@ pub async fn server(pool: Pool) -> io::Result<()> {
         App::new()
             // store db pool as Data object
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(calls.clone()))
+            .app_data(web::Data::new(dialer.clone()))
+            .app_data(web::Data::new(registration_status.clone()))
+            .app_data(web::PayloadConfig::new(UPLOAD_LIMIT))
             .wrap(middleware::Logger::default())
             .service(index)
             .service(voicemail_all)
             .service(del_voicemail)
             .service(voice_data)
             .service(modify_caller)
+            .service(active_calls)
+            .service(call_back)
+            .service(dials)
+            .service(registration)
+            .service(mailbox_all)
+            .service(modify_mailbox)
+            .service(greeting_rules_all)
+            .service(modify_greeting_rule)
+            .service(del_greeting_rule)
+            .service(greetings_all)
+            .service(upload_greeting)
+            .service(activate_greeting)
             .service(assets)
     })
     .bind(("0.0.0.0", 8080))?